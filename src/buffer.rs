@@ -0,0 +1,1231 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::str;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// これより大きいファイルはメモリに読み込まずに、必要なところだけ読む
+const LAZY_THRESHOLD: usize = 16 * 1024 * 1024;
+
+// 裏で一度に行の位置を調べるバイト数. 最初の 1 回分は開くときに調べて、すぐに表示できるようにする
+const INDEX_CHUNK: usize = 1024 * 1024;
+
+// 文字数を数えておく間隔 (バイト)
+const CHAR_BLOCK: usize = 4096;
+
+// 文字の区切りを決めるのに前後で見るバイト数. UTF-8 の 1 文字は最大 4 バイト
+const CHAR_CONTEXT: usize = 3;
+
+// ファイルから一度に読むバイト数と、読んだものを覚えておく数
+const FILE_BLOCK: usize = 64 * 1024;
+const CACHED_BLOCKS: usize = 16;
+
+// 裏で調べた範囲の長さと、その中の '\n' の位置と CHAR_BLOCK ごとの文字数
+struct Index {
+    len: usize,
+    newlines: Vec<usize>,
+    chars: Vec<usize>,
+}
+
+// 裏で行の位置を調べているファイルの続きを受け取る
+struct Indexer {
+    rx: Receiver<io::Result<Index>>,
+    total: usize, // 開いたときのファイルの大きさ
+}
+
+// 元の内容と追加した内容のどちらを指すか
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Source {
+    Original,
+    Add,
+}
+
+// ファイルから読んだブロックの番号と内容. 最近使ったものが前
+type Cache = RefCell<VecDeque<(usize, Rc<[u8]>)>>;
+
+// テキストの置き場所
+enum Store {
+    Memory(Vec<u8>),
+    // 大きなファイルはディスクに置いたまま pread で読む. メモリにマップすると、
+    // 開いている間に他のプロセスがファイルを切り詰めたときに SIGBUS で落ちてしまう
+    File { file: Arc<File>, cache: Cache },
+}
+
+// 足していくだけのテキスト. 行や文字の位置をすぐに求められるように数えておく
+struct Text {
+    store: Store,
+    len: usize,
+    // '\n' の位置
+    newlines: Vec<usize>,
+    // CHAR_BLOCK バイトごとに、そこより前の文字数
+    chars: Vec<usize>,
+    // ファイルを読めなかったときのエラー
+    error: RefCell<Option<io::Error>>,
+}
+
+impl Text {
+    fn new(store: Store) -> Self {
+        Self {
+            store,
+            len: 0,
+            newlines: vec![],
+            chars: vec![0],
+            error: RefCell::new(None),
+        }
+    }
+
+    // メモリに置いたテキストに足す
+    fn extend(&mut self, buf: &[u8]) {
+        if let Store::Memory(bytes) = &mut self.store {
+            bytes.extend_from_slice(buf);
+        }
+        // 末尾の数バイトは続きによって文字の区切りが変わるので、その前の区切りから数え直す
+        let k = self.len.saturating_sub(CHAR_CONTEXT) / CHAR_BLOCK;
+        let start = k * CHAR_BLOCK;
+        let ctx = start.saturating_sub(CHAR_CONTEXT);
+        let end = self.len + buf.len();
+        let mut counted = self.chars[k];
+        let window = self.slice(ctx, end);
+        let chars = count_blocks(&window, start - ctx, end - start, start, &mut counted);
+        self.chars.truncate(k + 1);
+        self.chars.extend(chars);
+        let len = self.len;
+        let newlines = buf.iter().enumerate().filter(|(_, &b)| b == b'\n');
+        self.newlines.extend(newlines.map(|(i, _)| len + i));
+        self.len = end;
+    }
+
+    fn extend_index(&mut self, index: Index) {
+        self.len += index.len;
+        self.newlines.extend(index.newlines);
+        self.chars.extend(index.chars);
+    }
+
+    // start..end の内容. ファイルを読めなかったところは 0 で埋めて、エラーを覚えておく
+    fn slice(&self, start: usize, end: usize) -> Cow<'_, [u8]> {
+        let (file, cache) = match &self.store {
+            Store::Memory(bytes) => return Cow::Borrowed(&bytes[start..end]),
+            Store::File { file, cache } => (file, cache),
+        };
+        let mut buf = Vec::with_capacity(end - start);
+        let mut pos = start;
+        while pos < end {
+            let k = pos / FILE_BLOCK;
+            let block_end = ((k + 1) * FILE_BLOCK).min(end);
+            let block = self.block(file, cache, k);
+            buf.extend_from_slice(&block[pos - k * FILE_BLOCK..block_end - k * FILE_BLOCK]);
+            pos = block_end;
+        }
+        Cow::Owned(buf)
+    }
+
+    // k 番目のブロック. 最近読んだものは読み直さない
+    fn block(&self, file: &File, cache: &Cache, k: usize) -> Rc<[u8]> {
+        let mut cache = cache.borrow_mut();
+        let start = k * FILE_BLOCK;
+        let len = (start + FILE_BLOCK).min(self.len) - start;
+        // 末尾のブロックは、行の位置を調べ進めると長くなるので読み直す
+        if let Some(i) = cache.iter().position(|(n, b)| *n == k && b.len() == len) {
+            let entry = cache.remove(i).unwrap();
+            cache.push_front(entry);
+            return Rc::clone(&cache[0].1);
+        }
+        let mut block = vec![0; len];
+        let error = match read_at(file, &mut block, start) {
+            Ok(n) if n == len => None,
+            // 開いた後に切り詰められていたら、読めないところは 0 のままにする
+            Ok(_) => Some(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file was truncated",
+            )),
+            Err(err) => Some(err),
+        };
+        let block: Rc<[u8]> = block.into();
+        match error {
+            // 読めなかったものは覚えておかずに、次も読み直す
+            Some(err) => {
+                self.error.borrow_mut().get_or_insert(err);
+            }
+            None => {
+                cache.push_front((k, Rc::clone(&block)));
+                cache.truncate(CACHED_BLOCKS);
+            }
+        }
+        block
+    }
+
+    // start..end を書き出す. 読めなければ 0 で埋めずにエラーにする
+    fn write_range<W: Write>(&self, start: usize, end: usize, writer: &mut W) -> io::Result<()> {
+        let file = match &self.store {
+            Store::Memory(bytes) => return writer.write_all(&bytes[start..end]),
+            Store::File { file, .. } => file,
+        };
+        let mut buf = vec![0; FILE_BLOCK];
+        let mut pos = start;
+        while pos < end {
+            let len = (end - pos).min(FILE_BLOCK);
+            file.read_exact_at(&mut buf[..len], pos as u64)?;
+            writer.write_all(&buf[..len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    fn newlines_before(&self, pos: usize) -> usize {
+        self.newlines.partition_point(|&p| p < pos)
+    }
+
+    fn chars_before(&self, pos: usize) -> usize {
+        let k = pos / CHAR_BLOCK;
+        let start = k * CHAR_BLOCK;
+        let (ctx, window) = self.char_window(start, pos);
+        self.chars[k] + count_chars(&window, ctx, start, pos)
+    }
+
+    // n 文字目の位置. なければ末尾
+    fn char_pos(&self, n: usize) -> usize {
+        let k = self.chars.partition_point(|&c| c <= n) - 1;
+        let start = k * CHAR_BLOCK;
+        let end = (start + CHAR_BLOCK).min(self.len);
+        let (ctx, window) = self.char_window(start, end);
+        nth_char(&window, ctx, start, end, n - self.chars[k]).unwrap_or(self.len)
+    }
+
+    // 文字の区切りを決めるために、start..end の前後の数バイトも含めて読む. 読み始めた位置も返す
+    fn char_window(&self, start: usize, end: usize) -> (usize, Cow<'_, [u8]>) {
+        let ctx = start.saturating_sub(CHAR_CONTEXT);
+        (ctx, self.slice(ctx, (end + CHAR_CONTEXT).min(self.len)))
+    }
+}
+
+// piece の中の文字数と改行の数も持っておく. 文字数は前後の piece によって変わるので、
+// 並べた後に recount_chars で数える
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Piece {
+    source: Source,
+    start: usize,
+    len: usize,
+    chars: usize,
+    newlines: usize,
+}
+
+// ある位置より前のバイト数、文字数、改行の数
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct Counts {
+    bytes: usize,
+    chars: usize,
+    newlines: usize,
+}
+
+// ピーステーブルでテキストを持つ. 元の内容は書き換えず、挿入した内容は add に足していく.
+// 行は '\n' で区切り、最後の行は改行で終わっていなくてもよい
+pub struct Buffer {
+    original: Text,
+    add: Text,
+    pieces: Vec<Piece>,
+    // 各 piece より前の合計. 最後はテキスト全体. 二分探索で位置から piece を探す.
+    // 編集するとその piece より後ろを全部足し直すので、編集は piece の数に比例する
+    offsets: Vec<Counts>,
+    // ファイルを読み終わるまでは Some. 読み終わっていない続きはテキストの末尾に足していく
+    indexer: Option<Indexer>,
+    // 裏で行の位置を調べられなかったときのエラー
+    read_error: Option<io::Error>,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::from(vec![])
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    fn from(original: Vec<u8>) -> Self {
+        let index = index(&original, 0, original.len(), 0, &mut 0);
+        let mut buffer = Self::with_original(Store::Memory(original));
+        buffer.append_original(index);
+        buffer
+    }
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 大きなファイルは先頭の行の位置だけ調べてすぐに返す
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with(path, LAZY_THRESHOLD, INDEX_CHUNK)
+    }
+
+    pub(crate) fn open_with<P: AsRef<Path>>(
+        path: P,
+        threshold: usize,
+        chunk: usize,
+    ) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < threshold {
+            let mut buf = Vec::with_capacity(len);
+            file.read_to_end(&mut buf)?;
+            return Ok(Self::from(buf));
+        }
+        Self::lazy(file, len, chunk)
+    }
+
+    // 先頭の chunk バイトの行の位置を調べて、残りは別スレッドで chunk バイトずつ調べる.
+    // 内容はディスクに置いたまま、表示や編集に要るところだけ読む
+    fn lazy(file: File, total: usize, chunk: usize) -> io::Result<Self> {
+        let file = Arc::new(file);
+        let mut counted = 0;
+        let first = index_file(&file, 0, chunk, &mut counted)?;
+        let more = first.len == chunk;
+        let mut buffer = Self::with_original(Store::File {
+            file: Arc::clone(&file),
+            cache: RefCell::new(VecDeque::new()),
+        });
+        buffer.append_original(first);
+        if !more {
+            return Ok(buffer);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut pos = chunk;
+        thread::spawn(move || loop {
+            let index = index_file(&file, pos, chunk, &mut counted);
+            let done = match &index {
+                // 開いた後に切り詰められていても、読めたところで終わる
+                Ok(index) if index.len == 0 => break,
+                Ok(index) => {
+                    pos += index.len;
+                    false
+                }
+                Err(_) => true,
+            };
+            // バッファが閉じられたら止める
+            if tx.send(index).is_err() || done {
+                break;
+            }
+        });
+        buffer.indexer = Some(Indexer { rx, total });
+        Ok(buffer)
+    }
+
+    fn with_original(store: Store) -> Self {
+        Self {
+            original: Text::new(store),
+            add: Text::new(Store::Memory(vec![])),
+            pieces: vec![],
+            offsets: vec![Counts::default()],
+            indexer: None,
+            read_error: None,
+        }
+    }
+
+    // 裏で調べた行の位置を取り込む. 新しく分かった内容があれば true
+    pub fn poll_index(&mut self) -> bool {
+        let mut updated = false;
+        while let Some(indexer) = &self.indexer {
+            match indexer.rx.try_recv() {
+                Ok(index) => {
+                    self.receive(index);
+                    updated = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.indexer = None,
+            }
+        }
+        updated
+    }
+
+    // 行の位置を全部調べ終わるまで待つ
+    pub fn wait_index(&mut self) {
+        while let Some(indexer) = &self.indexer {
+            match indexer.rx.recv() {
+                Ok(index) => self.receive(index),
+                Err(_) => self.indexer = None,
+            }
+        }
+    }
+
+    // y 行目が分かるまで、次の断片を 1 つずつ待つ. timeout を過ぎたらあきらめる.
+    // 行が分かっていれば true
+    pub fn wait_line(&mut self, y: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while y >= self.line_count() {
+            let indexer = match &self.indexer {
+                Some(indexer) => indexer,
+                None => break,
+            };
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match indexer.rx.recv_timeout(wait) {
+                Ok(index) => self.receive(index),
+                Err(RecvTimeoutError::Timeout) => return false,
+                Err(RecvTimeoutError::Disconnected) => self.indexer = None,
+            }
+        }
+        true
+    }
+
+    fn receive(&mut self, index: io::Result<Index>) {
+        match index {
+            Ok(index) => self.append_original(index),
+            Err(err) => {
+                self.read_error = Some(err);
+                self.indexer = None;
+            }
+        }
+    }
+
+    // 調べ終わった範囲をテキストの末尾に足す. まだ調べていない続きは、編集した内容も
+    // 含めたテキスト全体の後ろにあるので、いつも末尾でよい
+    fn append_original(&mut self, index: Index) {
+        if index.len == 0 {
+            return;
+        }
+        let start = self.original.len;
+        let len = index.len;
+        let end = self.len();
+        self.original.extend_index(index);
+        let i = match self.pieces.last().copied() {
+            Some(piece) if piece.source == Source::Original && piece.start + piece.len == start => {
+                let i = self.pieces.len() - 1;
+                self.pieces[i] = self.new_piece(Source::Original, piece.start, piece.len + len);
+                i
+            }
+            _ => {
+                let piece = self.new_piece(Source::Original, start, len);
+                self.pieces.push(piece);
+                self.pieces.len() - 1
+            }
+        };
+        self.update_offsets(i);
+        self.recount_chars(end, end + len);
+    }
+
+    // ファイルを読めなかったときのエラーを 1 度だけ返す. 開いた後に切り詰められたときも含む
+    pub fn take_read_error(&mut self) -> Option<io::Error> {
+        self.read_error
+            .take()
+            .or_else(|| self.original.error.borrow_mut().take())
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.indexer.is_some()
+    }
+
+    // 行の位置を調べ終わった割合 (%). 開いた後にファイルが大きくなっても 100 にはしない
+    pub fn index_progress(&self) -> Option<usize> {
+        let indexer = self.indexer.as_ref()?;
+        Some((self.original.len * 100 / indexer.total).min(99))
+    }
+
+    pub fn len(&self) -> usize {
+        self.total().bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 改行で終わっていない最後の行も 1 行と数える.
+    // 行の位置を調べている間は、分かっている改行までの行数を返す
+    pub fn line_count(&self) -> usize {
+        let newlines = self.total().newlines;
+        if self.is_indexing() || self.ends_with_newline() {
+            newlines
+        } else {
+            newlines + 1
+        }
+    }
+
+    // 空のときも改行で終わっているものとする
+    pub fn ends_with_newline(&self) -> bool {
+        match self.pieces.last() {
+            Some(piece) => {
+                let end = piece.start + piece.len;
+                *self.text(piece.source).slice(end - 1, end) == [b'\n']
+            }
+            None => true,
+        }
+    }
+
+    // 開いたファイルが改行で終わっていたか. 行の位置を調べ終わるまでは分からない
+    pub fn original_ends_with_newline(&self) -> Option<bool> {
+        if self.is_indexing() {
+            return None;
+        }
+        let len = self.original.len;
+        Some(len == 0 || *self.original.slice(len - 1, len) == [b'\n'])
+    }
+
+    // y 行目の先頭のバイト位置. 行がなければ末尾を返す
+    pub fn line_to_byte(&self, y: usize) -> usize {
+        match y {
+            0 => 0,
+            _ => self.newline_pos(y - 1).map_or(self.len(), |pos| pos + 1),
+        }
+    }
+
+    // at より前にある改行の数. つまり at を含む行
+    pub fn byte_to_line(&self, at: usize) -> usize {
+        if at >= self.len() {
+            return self.total().newlines;
+        }
+        let i = self.find_piece(at, |counts| counts.bytes);
+        let (piece, offset) = (self.pieces[i], self.offsets[i]);
+        let text = self.text(piece.source);
+        let end = piece.start + (at - offset.bytes);
+        offset.newlines + text.newlines_before(end) - text.newlines_before(piece.start)
+    }
+
+    // 先頭から c 文字目のバイト位置. 正しい UTF-8 の並びは 1 文字、それ以外のバイトは
+    // それぞれ 1 文字と数える
+    pub fn char_to_byte(&self, c: usize) -> usize {
+        if c >= self.total().chars {
+            return self.len();
+        }
+        let i = self.find_piece(c, |counts| counts.chars);
+        let (piece, offset) = (self.pieces[i], self.offsets[i]);
+        let (inner_start, inner_end) = self.inner_bounds(i);
+        let mut n = c - offset.chars;
+
+        let (ctx, window) = self.char_window(offset.bytes, inner_start);
+        if let Some(pos) = nth_char(&window, ctx, offset.bytes, inner_start, n) {
+            return pos;
+        }
+        n -= count_chars(&window, ctx, offset.bytes, inner_start);
+        let inner = self.piece_chars(i, inner_start, inner_end);
+        if n < inner {
+            let text = self.text(piece.source);
+            let from = text.chars_before(piece.start + (inner_start - offset.bytes));
+            return offset.bytes + (text.char_pos(from + n) - piece.start);
+        }
+        n -= inner;
+        let end = offset.bytes + piece.len;
+        let (ctx, window) = self.char_window(inner_end, end);
+        nth_char(&window, ctx, inner_end, end, n).unwrap_or(end)
+    }
+
+    pub fn byte_to_char(&self, at: usize) -> usize {
+        if at >= self.len() {
+            return self.total().chars;
+        }
+        let i = self.find_piece(at, |counts| counts.bytes);
+        let offset = self.offsets[i];
+        offset.chars + self.piece_chars(i, offset.bytes, at)
+    }
+
+    // y 行目の内容. 改行は含めない
+    pub fn line(&self, y: usize) -> Vec<u8> {
+        let (start, end) = self.line_bounds(y);
+        let mut line = self.slice(start, end);
+        let eol = eol_len(&line, end < self.len() || self.ends_with_newline());
+        line.truncate(line.len() - eol);
+        line
+    }
+
+    // y 行目の改行. 改行で終わっていない最後の行は空
+    pub fn line_ending(&self, y: usize) -> &'static [u8] {
+        let (start, end) = self.line_bounds(y);
+        if end == start || self.newline_pos(y).is_none() {
+            return b"";
+        }
+        let tail = self.slice(end.saturating_sub(2).max(start), end);
+        match eol_len(&tail, true) {
+            2 => b"\r\n",
+            _ => b"\n",
+        }
+    }
+
+    pub fn lines(&self) -> Lines<'_> {
+        Lines { buffer: self, y: 0 }
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> Vec<u8> {
+        let end = end.min(self.len());
+        if start >= end {
+            return vec![];
+        }
+        let mut buf = Vec::with_capacity(end - start);
+        let first = self.find_piece(start, |counts| counts.bytes);
+        for (piece, offset) in self.pieces[first..].iter().zip(&self.offsets[first..]) {
+            let (ps, pe) = (offset.bytes, offset.bytes + piece.len);
+            if ps >= end {
+                break;
+            }
+            let text = self.text(piece.source);
+            let from = piece.start + (start.max(ps) - ps);
+            let to = piece.start + (end.min(pe) - ps);
+            buf.extend_from_slice(&text.slice(from, to));
+        }
+        buf
+    }
+
+    // 先頭から順に書き出す. 全体をメモリにコピーしない
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for piece in self.pieces.iter() {
+            let text = self.text(piece.source);
+            text.write_range(piece.start, piece.start + piece.len, writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.slice(0, self.len())
+    }
+
+    pub fn insert(&mut self, at: usize, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let at = at.min(self.len());
+        let start = self.add.len;
+        self.add.extend(bytes);
+
+        // at を含む piece. 末尾なら pieces.len()
+        let i = if at == self.len() {
+            self.pieces.len()
+        } else {
+            self.find_piece(at, |counts| counts.bytes)
+        };
+        let offset = self.offsets[i].bytes;
+        // 直前に挿入した位置の続きなら piece を伸ばすだけで済む
+        if i > 0 && at == offset {
+            let prev = self.pieces[i - 1];
+            if prev.source == Source::Add && prev.start + prev.len == start {
+                self.pieces[i - 1] =
+                    self.new_piece(Source::Add, prev.start, prev.len + bytes.len());
+                self.update_offsets(i - 1);
+                self.recount_chars(at, at + bytes.len());
+                return;
+            }
+        }
+
+        let new = self.new_piece(Source::Add, start, bytes.len());
+        if at == offset {
+            self.pieces.insert(i, new);
+        } else {
+            let (left, right) = self.split_piece(self.pieces[i], at - offset);
+            self.pieces.splice(i..=i, vec![left, new, right]);
+        }
+        self.update_offsets(i);
+        self.recount_chars(at, at + bytes.len());
+    }
+
+    // start..end を削除して、削除した内容を返す
+    pub fn delete(&mut self, start: usize, end: usize) -> Vec<u8> {
+        let end = end.min(self.len());
+        if start >= end {
+            return vec![];
+        }
+        let removed = self.slice(start, end);
+
+        // start より前の piece は変わらない
+        let first = self.find_piece(start, |counts| counts.bytes);
+        let mut pieces = Vec::with_capacity(self.pieces.len() - first + 1);
+        for (piece, offset) in self.pieces[first..].iter().zip(&self.offsets[first..]) {
+            let (ps, pe) = (offset.bytes, offset.bytes + piece.len);
+            if pe <= start || ps >= end {
+                pieces.push(*piece);
+                continue;
+            }
+            if ps < start {
+                pieces.push(self.split_piece(*piece, start - ps).0);
+            }
+            if pe > end {
+                pieces.push(self.split_piece(*piece, end - ps).1);
+            }
+        }
+        self.pieces.splice(first.., pieces);
+        self.update_offsets(first);
+        self.recount_chars(start, start);
+        removed
+    }
+
+    fn text(&self, source: Source) -> &Text {
+        match source {
+            Source::Original => &self.original,
+            Source::Add => &self.add,
+        }
+    }
+
+    fn new_piece(&self, source: Source, start: usize, len: usize) -> Piece {
+        let text = self.text(source);
+        let end = start + len;
+        Piece {
+            source,
+            start,
+            len,
+            chars: 0,
+            newlines: text.newlines_before(end) - text.newlines_before(start),
+        }
+    }
+
+    // piece を at バイト目で 2 つに分ける
+    fn split_piece(&self, piece: Piece, at: usize) -> (Piece, Piece) {
+        let left = self.new_piece(piece.source, piece.start, at);
+        let right = Piece {
+            start: piece.start + at,
+            len: piece.len - at,
+            chars: 0,
+            newlines: piece.newlines - left.newlines,
+            ..piece
+        };
+        (left, right)
+    }
+
+    // バッファの start..end を書き換えた後に、前後の数バイトも含めて重なる piece の文字数を
+    // 数え直す. 書き換えたところの前後では文字の区切りが変わることがある
+    fn recount_chars(&mut self, start: usize, end: usize) {
+        let from = start.saturating_sub(CHAR_CONTEXT);
+        let to = end + CHAR_CONTEXT;
+        if self.pieces.is_empty() || from >= self.len() {
+            return;
+        }
+        let first = self.find_piece(from, |counts| counts.bytes);
+        let mut i = first;
+        while i < self.pieces.len() && self.offsets[i].bytes < to {
+            let (ps, pe) = (self.offsets[i].bytes, self.offsets[i + 1].bytes);
+            self.pieces[i].chars = self.piece_chars(i, ps, pe);
+            i += 1;
+        }
+        self.update_offsets(first);
+    }
+
+    // i 番目の piece の中で、バッファの start..end にある文字の数.
+    // piece の両端の数バイトは前後の piece によって区切りが変わるのでバッファから読んで数え、
+    // 内側はテキストで数えておいたものを使う
+    fn piece_chars(&self, i: usize, start: usize, end: usize) -> usize {
+        if start >= end {
+            return 0;
+        }
+        let (piece, offset) = (self.pieces[i], self.offsets[i]);
+        let (inner_start, inner_end) = self.inner_bounds(i);
+        let (from, to) = (start.max(inner_start), end.min(inner_end));
+        let inner = if from < to {
+            let text = self.text(piece.source);
+            let to_text = |pos| piece.start + (pos - offset.bytes);
+            text.chars_before(to_text(to)) - text.chars_before(to_text(from))
+        } else {
+            0
+        };
+        let count = |start, end| {
+            let (ctx, window) = self.char_window(start, end);
+            count_chars(&window, ctx, start, end)
+        };
+        count(start, end.min(inner_start)) + inner + count(start.max(inner_end), end)
+    }
+
+    // i 番目の piece で、前後の piece によらずに文字の区切りが決まる範囲
+    fn inner_bounds(&self, i: usize) -> (usize, usize) {
+        let (start, end) = (self.offsets[i].bytes, self.offsets[i + 1].bytes);
+        let inner_start = (start + CHAR_CONTEXT).min(end);
+        let inner_end = end.saturating_sub(CHAR_CONTEXT).max(inner_start);
+        (inner_start, inner_end)
+    }
+
+    // 文字の区切りを決めるために、バッファの start..end の前後の数バイトも含めて読む.
+    // 読み始めた位置も返す
+    fn char_window(&self, start: usize, end: usize) -> (usize, Vec<u8>) {
+        if start >= end {
+            return (start, vec![]);
+        }
+        let ctx = start.saturating_sub(CHAR_CONTEXT);
+        (ctx, self.slice(ctx, end + CHAR_CONTEXT))
+    }
+
+    // from 番目より後ろの piece の合計を数え直す
+    fn update_offsets(&mut self, from: usize) {
+        self.offsets.truncate(from + 1);
+        for piece in self.pieces[from..].iter() {
+            let last = self.offsets[self.offsets.len() - 1];
+            self.offsets.push(Counts {
+                bytes: last.bytes + piece.len,
+                chars: last.chars + piece.chars,
+                newlines: last.newlines + piece.newlines,
+            });
+        }
+    }
+
+    fn total(&self) -> Counts {
+        self.offsets[self.offsets.len() - 1]
+    }
+
+    // key で数えて n 番目を含む piece. n は全体より小さいこと
+    fn find_piece<F: Fn(&Counts) -> usize>(&self, n: usize, key: F) -> usize {
+        self.offsets.partition_point(|counts| key(counts) <= n) - 1
+    }
+
+    // n 番目の改行のバイト位置
+    fn newline_pos(&self, n: usize) -> Option<usize> {
+        if n >= self.total().newlines {
+            return None;
+        }
+        let i = self.find_piece(n, |counts| counts.newlines);
+        let (piece, offset) = (self.pieces[i], self.offsets[i]);
+        let text = self.text(piece.source);
+        let pos = text.newlines[text.newlines_before(piece.start) + (n - offset.newlines)];
+        Some(offset.bytes + (pos - piece.start))
+    }
+
+    // y 行目の先頭と、次の行の先頭
+    fn line_bounds(&self, y: usize) -> (usize, usize) {
+        let start = self.line_to_byte(y);
+        let end = self.newline_pos(y).map_or(self.len(), |pos| pos + 1);
+        (start, end)
+    }
+}
+
+pub struct Lines<'a> {
+    buffer: &'a Buffer,
+    y: usize,
+}
+
+impl Iterator for Lines<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.y >= self.buffer.line_count() {
+            return None;
+        }
+        self.y += 1;
+        Some(self.buffer.line(self.y - 1))
+    }
+}
+
+// pos から最大 len バイト読んで、行の位置と文字数を調べる. 短ければファイルの終わり.
+// 文字の区切りを決めるために前後の数バイトも読む
+fn index_file(file: &File, pos: usize, len: usize, counted: &mut usize) -> io::Result<Index> {
+    let ctx = pos.saturating_sub(CHAR_CONTEXT);
+    let mut buf = vec![0; pos - ctx + len + CHAR_CONTEXT];
+    let read = read_at(file, &mut buf, ctx)?;
+    let len = read.saturating_sub(pos - ctx).min(len);
+    Ok(index(&buf[..read], pos - ctx, len, pos, counted))
+}
+
+// pos から buf がいっぱいになるかファイルの終わりまで読んで、読んだバイト数を返す
+fn read_at(file: &File, buf: &mut [u8], pos: usize) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], (pos + read) as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+// base から始まる buf[from..from + len] の '\n' の位置と、CHAR_BLOCK の区切りごとのそこより
+// 前の文字数. その前後は文字の区切りを決めるためだけに使う.
+// counted は base より前の文字数で、数えた分を足す
+fn index(buf: &[u8], from: usize, len: usize, base: usize, counted: &mut usize) -> Index {
+    let newlines = buf[from..from + len]
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b == b'\n')
+        .map(|(i, _)| base + i)
+        .collect();
+    Index {
+        len,
+        newlines,
+        chars: count_blocks(buf, from, len, base, counted),
+    }
+}
+
+// base から始まる buf[from..from + len] の文字を数えて、CHAR_BLOCK の区切りごとのそこより前の
+// 文字数を返す
+fn count_blocks(
+    buf: &[u8],
+    from: usize,
+    len: usize,
+    base: usize,
+    counted: &mut usize,
+) -> Vec<usize> {
+    let mut chars = vec![];
+    let end = from + len;
+    // 次の CHAR_BLOCK の区切り. buf の中の位置
+    let mut block = from + (CHAR_BLOCK - base % CHAR_BLOCK);
+    let mut pos = 0;
+    while pos < end {
+        if pos >= from {
+            while block <= pos {
+                chars.push(*counted);
+                block += CHAR_BLOCK;
+            }
+            *counted += 1;
+        }
+        pos += char_len(&buf[pos..]);
+    }
+    while block <= end {
+        chars.push(*counted);
+        block += CHAR_BLOCK;
+    }
+    chars
+}
+
+// 行末の改行のバイト数. 改行の前にない \r は行の内容とする
+fn eol_len(line: &[u8], terminated: bool) -> usize {
+    if !terminated || line.last() != Some(&b'\n') {
+        0
+    } else if line.ends_with(b"\r\n") {
+        2
+    } else {
+        1
+    }
+}
+
+// ctx から始まる window の中で、start..end にある文字の数
+fn count_chars(window: &[u8], ctx: usize, start: usize, end: usize) -> usize {
+    let mut chars = 0;
+    let mut pos = ctx;
+    while pos < end {
+        if pos >= start {
+            chars += 1;
+        }
+        pos += char_len(&window[pos - ctx..]);
+    }
+    chars
+}
+
+// ctx から始まる window の中で、start..end にある n 番目の文字の位置
+fn nth_char(window: &[u8], ctx: usize, start: usize, end: usize, mut n: usize) -> Option<usize> {
+    let mut pos = ctx;
+    while pos < end {
+        if pos >= start {
+            if n == 0 {
+                return Some(pos);
+            }
+            n -= 1;
+        }
+        pos += char_len(&window[pos - ctx..]);
+    }
+    None
+}
+
+// buf の先頭の文字のバイト数. 行の表示と同じく、正しい UTF-8 の並びは 1 文字、それ以外の
+// バイトはそれぞれ 1 文字とする
+fn char_len(buf: &[u8]) -> usize {
+    let len = match buf[0] {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return 1,
+    };
+    match buf.get(..len) {
+        Some(seq) if str::from_utf8(seq).is_ok() => len,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(buffer: &Buffer) -> Vec<Vec<u8>> {
+        buffer.lines().collect()
+    }
+
+    #[test]
+    fn test_lines() {
+        let buffer = Buffer::new();
+        assert_eq!(buffer.line_count(), 0);
+        assert!(buffer.ends_with_newline());
+
+        let buffer = Buffer::from(b"ab\r\ncd\n\nef".to_vec());
+        assert_eq!(buffer.line_count(), 4);
+        assert_eq!(lines(&buffer), vec![&b"ab"[..], b"cd", b"", b"ef"]);
+        assert_eq!(buffer.line_ending(0), b"\r\n");
+        assert_eq!(buffer.line_ending(1), b"\n");
+        assert_eq!(buffer.line_ending(3), b"");
+        assert!(!buffer.ends_with_newline());
+
+        // 改行で終わっていれば最後の空行は数えない
+        let buffer = Buffer::from(b"a\n".to_vec());
+        assert_eq!(lines(&buffer), vec![b"a"]);
+
+        // 改行の前にない \r は行の内容
+        let buffer = Buffer::from(b"a\rb\r".to_vec());
+        assert_eq!(lines(&buffer), vec![b"a\rb\r"]);
+    }
+
+    #[test]
+    fn test_line_to_byte() {
+        let buffer = Buffer::from(b"ab\ncd\nef".to_vec());
+        assert_eq!(buffer.line_to_byte(0), 0);
+        assert_eq!(buffer.line_to_byte(1), 3);
+        assert_eq!(buffer.line_to_byte(2), 6);
+        assert_eq!(buffer.line_to_byte(3), 8);
+
+        assert_eq!(buffer.byte_to_line(0), 0);
+        assert_eq!(buffer.byte_to_line(2), 0);
+        assert_eq!(buffer.byte_to_line(3), 1);
+        assert_eq!(buffer.byte_to_line(8), 2);
+    }
+
+    #[test]
+    fn test_char_to_byte() {
+        let buffer = Buffer::from(b"a\xe3\x81\x82\xffb".to_vec());
+        assert_eq!(buffer.char_to_byte(1), 1);
+        assert_eq!(buffer.char_to_byte(2), 4);
+        assert_eq!(buffer.char_to_byte(3), 5);
+        assert_eq!(buffer.char_to_byte(9), buffer.len());
+        assert_eq!(buffer.byte_to_char(5), 3);
+
+        // 単独の継続バイトや途切れた並びも、1 バイトずつ 1 文字と数える
+        let buffer = Buffer::from(b"a\x80b\xe3\x81c".to_vec());
+        assert_eq!(buffer.char_to_byte(1), 1);
+        assert_eq!(buffer.char_to_byte(2), 2);
+        assert_eq!(buffer.char_to_byte(4), 4);
+        assert_eq!(buffer.char_to_byte(5), 5);
+        assert_eq!(buffer.byte_to_char(buffer.len()), 6);
+
+        // 挿入して正しい並びになったら 1 文字
+        let mut buffer = Buffer::from(b"\xe3\x81".to_vec());
+        assert_eq!(buffer.byte_to_char(buffer.len()), 2);
+        buffer.insert(2, b"\x82");
+        assert_eq!(buffer.byte_to_char(buffer.len()), 1);
+        assert_eq!(buffer.char_to_byte(1), 3);
+        buffer.delete(1, 2);
+        assert_eq!(buffer.byte_to_char(buffer.len()), 2);
+    }
+
+    #[test]
+    fn test_counts() {
+        // 数えておいた値で求めた位置が、先頭から数えた位置と一致する
+        fn check(buffer: &Buffer) {
+            let text = buffer.to_vec();
+            // 行の表示と同じように区切る
+            let mut starts = vec![];
+            let mut pos = 0;
+            for chunk in text.utf8_chunks() {
+                let valid = chunk.valid();
+                starts.extend(valid.char_indices().map(|(i, _)| pos + i));
+                pos += valid.len();
+                starts.extend(pos..pos + chunk.invalid().len());
+                pos += chunk.invalid().len();
+            }
+            let mut chars = 0;
+            let mut lines = 0;
+            for (i, &b) in text.iter().enumerate() {
+                assert_eq!(buffer.byte_to_line(i), lines);
+                assert_eq!(buffer.byte_to_char(i), chars);
+                if starts.binary_search(&i).is_ok() {
+                    assert_eq!(buffer.char_to_byte(chars), i);
+                    chars += 1;
+                }
+                if b == b'\n' {
+                    lines += 1;
+                    assert_eq!(buffer.line_to_byte(lines), i + 1);
+                }
+            }
+            assert_eq!(buffer.char_to_byte(chars), text.len());
+            assert_eq!(buffer.byte_to_char(text.len()), chars);
+            assert_eq!(buffer.byte_to_line(text.len()), lines);
+            assert_eq!(buffer.line_to_byte(lines + 1), text.len());
+        }
+
+        // CHAR_BLOCK をまたぐ長さで、マルチバイト文字と改行と不正なバイトを混ぜる
+        let text = [&b"a\xe3\x81\x82\n\xff\x80"[..]]
+            .repeat(CHAR_BLOCK / 3)
+            .concat();
+        let mut buffer = Buffer::from(text);
+        check(&buffer);
+
+        let mut seed = 1u32;
+        let mut next = |n: usize| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 8) as usize % n.max(1)
+        };
+        for i in 0..20 {
+            let at = next(buffer.len());
+            if i % 3 == 0 {
+                buffer.delete(at, at + next(CHAR_BLOCK));
+            } else if i % 3 == 1 {
+                buffer.insert(at, "x\nう".repeat(next(5)).as_bytes());
+            } else {
+                // 前後とつながって文字の区切りが変わるバイト
+                buffer.insert(at, &b"\x81\x82\xe3"[..next(4)]);
+            }
+        }
+        check(&buffer);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut buffer = Buffer::from(b"ac\nd".to_vec());
+        buffer.insert(1, b"b");
+        buffer.insert(2, b"x\ny");
+        assert_eq!(buffer.to_vec(), b"abx\nyc\nd");
+        assert_eq!(buffer.line_count(), 3);
+        assert_eq!(buffer.line(1), b"yc");
+
+        // 続けて挿入したら piece は増えない
+        let pieces = buffer.pieces.len();
+        buffer.insert(5, b"z");
+        assert_eq!(buffer.pieces.len(), pieces);
+
+        buffer.insert(0, b"<");
+        buffer.insert(buffer.len(), b">\n");
+        assert_eq!(buffer.to_vec(), b"<abx\nyzc\nd>\n");
+        assert_eq!(buffer.line_count(), 3);
+
+        let mut buffer = Buffer::new();
+        buffer.insert(0, b"hi");
+        assert_eq!(lines(&buffer), vec![b"hi"]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut buffer = Buffer::from(b"ab\ncd".to_vec());
+        buffer.insert(5, b"\nef");
+        assert_eq!(buffer.delete(1, 4), b"b\nc");
+        assert_eq!(buffer.to_vec(), b"ad\nef");
+        assert_eq!(buffer.line_count(), 2);
+
+        // piece をまたいで削除
+        assert_eq!(buffer.delete(1, 4), b"d\ne");
+        assert_eq!(buffer.to_vec(), b"af");
+        assert_eq!(buffer.line_count(), 1);
+
+        assert_eq!(buffer.delete(1, 1), b"");
+        assert_eq!(buffer.delete(0, 9), b"af");
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.line_count(), 0);
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("kirocode-buffer-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_lazy_index() {
+        // 先頭の 4 バイトだけ調べて、残りは裏で調べる
+        let path = temp_path("index");
+        std::fs::write(&path, b"ab\ncd\nef\ngh").unwrap();
+        let mut buffer = Buffer::open_with(&path, 0, 4).unwrap();
+        assert!(buffer.is_indexing());
+        assert_eq!(buffer.index_progress(), Some(36));
+        assert_eq!(buffer.line_count(), 1);
+        assert_eq!(buffer.line(0), b"ab");
+        assert_eq!(buffer.original_ends_with_newline(), None);
+
+        // 調べ終わる前に編集してもよい
+        buffer.insert(0, b"x\n");
+        assert_eq!(buffer.line_count(), 2);
+
+        // 必要な行が分かるまでだけ待つ
+        assert!(buffer.wait_line(3, Duration::from_secs(10)));
+        assert!(buffer.line_count() > 3);
+        assert!(buffer.wait_line(9, Duration::from_secs(10)));
+
+        buffer.wait_index();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!buffer.is_indexing());
+        assert_eq!(buffer.index_progress(), None);
+        assert_eq!(buffer.original_ends_with_newline(), Some(false));
+        assert_eq!(lines(&buffer), vec![&b"x"[..], b"ab", b"cd", b"ef", b"gh"]);
+        assert!(!buffer.poll_index());
+    }
+
+    #[test]
+    fn test_lazy_chars() {
+        // 裏で少しずつ調べても、文字の区切りはまとめて調べたときと同じ
+        let path = temp_path("lazy-chars");
+        let text = [&b"a\xe3\x81\x82\x80\xe3\x81\n"[..]]
+            .repeat(CHAR_BLOCK / 2)
+            .concat();
+        std::fs::write(&path, &text).unwrap();
+        let mut buffer = Buffer::open_with(&path, 0, 7).unwrap();
+        buffer.wait_index();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = Buffer::from(text);
+        for i in (0..=buffer.len()).step_by(5) {
+            assert_eq!(buffer.byte_to_char(i), expected.byte_to_char(i));
+        }
+        assert_eq!(buffer.char_to_byte(4000), expected.char_to_byte(4000));
+    }
+
+    #[test]
+    fn test_open() {
+        let path = temp_path("open");
+        std::fs::write(&path, b"a\nb\n").unwrap();
+        let buffer = Buffer::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!buffer.is_indexing());
+        assert_eq!(lines(&buffer), vec![b"a", b"b"]);
+    }
+
+    #[test]
+    fn test_open_lazy() {
+        let path = temp_path("lazy");
+        let data: Vec<u8> = (0..200_000)
+            .flat_map(|i| format!("{}\n", i).into_bytes())
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+
+        // 小さいしきい値で、ディスクに置いたまま読む道を通す
+        let mut buffer = Buffer::open_with(&path, 16, 1024).unwrap();
+        assert!(matches!(buffer.original.store, Store::File { .. }));
+        assert!(buffer.is_indexing());
+        assert_eq!(buffer.len(), 1024);
+        assert_eq!(buffer.line(0), b"0");
+
+        buffer.wait_index();
+        assert!(buffer.take_read_error().is_none());
+        assert_eq!(buffer.line_count(), 200_000);
+        assert_eq!(buffer.line(199_999), b"199999");
+        assert_eq!(buffer.to_vec(), data);
+
+        // 読んだブロックは少しだけ覚えておく
+        if let Store::File { cache, .. } = &buffer.original.store {
+            assert_eq!(cache.borrow().len(), CACHED_BLOCKS);
+        }
+
+        // 編集した内容と合わせて書き出す
+        buffer.insert(2, b"x");
+        let mut saved = vec![];
+        buffer.write_to(&mut saved).unwrap();
+        assert_eq!(&saved[..4], b"0\nx1");
+        assert_eq!(saved.len(), data.len() + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_truncated() {
+        let path = temp_path("truncated");
+        std::fs::write(&path, b"abc\n".repeat(3 * FILE_BLOCK / 4)).unwrap();
+        let mut buffer = Buffer::open_with(&path, 16, 1024).unwrap();
+        buffer.wait_index();
+
+        // 開いた後に他のプロセスが切り詰めても落ちない. 読めないところは 0 にして、エラーを返す
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(10)
+            .unwrap();
+        assert_eq!(buffer.line(1), b"abc");
+        // まだ読んでいないブロック
+        assert_eq!(buffer.line(FILE_BLOCK / 4 + 1), b"\0\0\0\0");
+        assert_eq!(
+            buffer.take_read_error().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(buffer.take_read_error().is_none());
+
+        // 保存するときは 0 で埋めずにエラーにする
+        assert!(buffer.write_to(&mut vec![]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
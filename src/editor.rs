@@ -1,21 +1,64 @@
-use crate::error::Result;
-use crate::input::{InputSeq, KeySeq};
-use crate::screen::Screen;
+use crate::buffer::Buffer;
+use crate::error::{Error, Result};
+use crate::input::{self, InputSeq, KeySeq, MouseButton, MouseKind};
+use crate::row::EditorRow;
+use crate::screen::{Screen, Status};
+use crate::syntax::{Highlight, HlState, Syntax};
+use crate::undo::{Change, EditKind, History, Transaction};
 
-use std::fs::File;
-use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+// 改行コードを調べる行数
+const DETECT_LINES: usize = 10_000;
+
+// ホイールを 1 回まわしたときにずらす行数
+const WHEEL_LINES: usize = 3;
+
+// 未保存の変更があるときに終了するまでに必要な Ctrl-Q の回数
+const QUIT_TIMES: usize = 3;
+
+// 行の位置を調べている途中で、カーソルのある行が分かるまで待つ時間
+const INDEX_WAIT: Duration = Duration::from_millis(200);
+
+// 一時ファイルや復旧用のファイルの名前が使われているときに、別の名前を試す回数
+const NAME_TRIES: usize = 100;
+
+const HELP_MESSAGE: &str =
+    "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find | Ctrl-U = undo | Ctrl-Y = redo | Ctrl-Z = suspend";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Mixed,
+}
 
 pub struct Editor<I: Iterator<Item = Result<InputSeq>>, W: Write> {
     screen: Screen<W>,
     input: I,
-    buf_rows: usize,
-    rows: Vec<EditorRow>,
-}
-
-pub struct EditorRow {
-    pub size: usize,
-    pub buf: Vec<u8>,
+    typed: VecDeque<InputSeq>, // 端末への問い合わせの間に読んでしまったキー
+    buffer: Buffer,
+    filepath: Option<PathBuf>,
+    dirty: usize,
+    quit_times: usize,
+    line_ending: LineEnding,
+    syntax: Option<&'static Syntax>,
+    // 行末の時点のハイライトの状態. 先頭から計算済みの行の分だけ持つ
+    hl_states: Vec<HlState>,
+    history: History,
+    pending: Vec<Change>, // まだ取り消し単位にしていない変更
+    search_match: Option<(usize, usize, usize)>, // 検索で一致した (行, 開始, 終了)
+    selection: Option<(usize, usize)>, // ドラッグで選択を始めた位置 (cx, cy)
+    clipboard: Vec<u8>,   // Ctrl-C でコピーした内容
+    new_file: bool,       // 開いたファイルがまだない
+    read_only: bool,
 }
 
 impl<I, W> Editor<I, W>
@@ -23,24 +66,26 @@ where
     I: Iterator<Item = Result<InputSeq>>,
     W: Write,
 {
-    pub fn open<P: AsRef<Path>>(filepath: P, mut input: I, output: W) -> Result<Self> {
-        let screen = Screen::new(None, &mut input, output)?;
-
-        let mut editor = Self {
-            screen,
-            input,
-            buf_rows: 0,
-            rows: vec![],
-        };
+    pub fn open<P: AsRef<Path>>(filepath: P, input: I, output: W) -> Result<Self> {
+        let mut editor = Self::new(input, output)?;
+        editor.filepath = Some(filepath.as_ref().to_path_buf());
+        editor.syntax = Syntax::select(&filepath);
 
-        if let Ok(lines) = read_lines(filepath) {
-            for line in lines {
-                if let Ok(ip) = line {
-                    let buf = ip.into_bytes();
-                    let size = buf.len();
-                    editor.append_row(buf, size);
-                }
+        match open_buffer(filepath.as_ref()) {
+            Ok(buffer) => {
+                editor.buffer = buffer;
+                editor.line_ending = detect_line_ending(&editor.buffer);
             }
+            // 保存したときに作る
+            Err(Error::NotFound(_)) => editor.new_file = true,
+            // 読めないファイルを空で上書きしないように、読み取り専用にする
+            Err(err @ Error::PermissionDenied(_)) => {
+                editor.read_only = true;
+                editor
+                    .screen
+                    .set_message(format!("WARNING: {} (read-only)", err));
+            }
+            Err(err) => return Err(err),
         }
 
         Ok(editor)
@@ -52,24 +97,574 @@ where
         let editor = Self {
             screen,
             input,
-            buf_rows: 0,
-            rows: vec![],
+            typed: VecDeque::new(),
+            buffer: Buffer::new(),
+            filepath: None,
+            dirty: 0,
+            quit_times: QUIT_TIMES,
+            line_ending: LineEnding::Lf,
+            syntax: None,
+            hl_states: vec![],
+            history: History::new(),
+            pending: vec![],
+            search_match: None,
+            selection: None,
+            clipboard: vec![],
+            new_file: false,
+            read_only: false,
         };
 
         Ok(editor)
     }
 
-    fn append_row(&mut self, buf: Vec<u8>, len: usize) {
-        self.rows.push(EditorRow { size: len, buf });
-        self.buf_rows += 1;
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    // 最後に保存してからの変更回数
+    pub fn dirty(&self) -> usize {
+        self.dirty
+    }
+
+    // 対応している端末なら kitty のキーボードプロトコルを使う. edit の前に呼ぶ
+    pub fn detect_kitty_keyboard(&mut self) -> Result<bool> {
+        let mut typed = vec![];
+        let kitty = self
+            .screen
+            .detect_kitty_keyboard(&mut self.input, &mut typed)?;
+        self.typed.extend(typed);
+        Ok(kitty)
+    }
+
+    // 先に読んでしまったキーがあればそれから返す
+    fn next_key(&mut self) -> Option<Result<InputSeq>> {
+        match self.typed.pop_front() {
+            Some(seq) => Some(Ok(seq)),
+            None => self.input.next(),
+        }
+    }
+
+    pub fn set_tab_stop(&mut self, tab_stop: usize) {
+        self.screen.set_tab_stop(tab_stop);
+    }
+
+    pub fn filetype(&self) -> Option<&'static str> {
+        self.syntax.map(|syntax| syntax.filetype)
+    }
+
+    fn row(&self, y: usize) -> EditorRow {
+        EditorRow::new(self.buffer.line(y), self.screen.tab_stop())
+    }
+
+    // 先頭から n 行目までのハイライトの状態を計算しておく
+    fn ensure_hl_states(&mut self, n: usize) {
+        if self.syntax.is_none() {
+            return;
+        }
+        let n = n.min(self.buffer.line_count());
+        while self.hl_states.len() < n {
+            let y = self.hl_states.len();
+            let start = self.hl_states.last().copied().unwrap_or(HlState::Normal);
+            let state = self.row(y).update_highlight(self.syntax, start);
+            self.hl_states.push(state);
+        }
+    }
+
+    // y 行目が変わり、その後ろに inserted 行増えて removed 行減ったときに、
+    // 行末の状態が前と同じになるまでハイライトし直す
+    fn update_highlight(&mut self, y: usize, inserted: usize, removed: usize) {
+        if y + removed >= self.hl_states.len() {
+            // 計算していない行は表示するときに計算する
+            self.hl_states.truncate(y);
+            return;
+        }
+        let placeholder = std::iter::repeat_n(HlState::Normal, inserted);
+        self.hl_states.splice(y..y + removed, placeholder);
+
+        for k in y..self.hl_states.len() {
+            let start = if k > 0 {
+                self.hl_states[k - 1]
+            } else {
+                HlState::Normal
+            };
+            let state = self.row(k).update_highlight(self.syntax, start);
+            if k >= y + inserted && self.hl_states[k] == state {
+                break;
+            }
+            self.hl_states[k] = state;
+        }
+    }
+
+    // 末尾に追加した行を改行で終えるか. 開いたファイルに合わせる.
+    // 大きなファイルの末尾は行の位置を調べ終わるまで分からないので、それまでは改行で終える
+    fn final_newline(&self) -> bool {
+        self.buffer.original_ends_with_newline().unwrap_or(true)
+    }
+
+    // 新しく追加する行の改行
+    fn default_eol(&self) -> &'static [u8] {
+        let crlf = match self.line_ending {
+            LineEnding::Lf => false,
+            LineEnding::CrLf => true,
+            LineEnding::Mixed => {
+                let n = self.buffer.line_count();
+                n > 0 && self.buffer.line_ending(n - 1) == b"\r\n"
+            }
+        };
+        if crlf {
+            b"\r\n"
+        } else {
+            b"\n"
+        }
+    }
+
+    // y 行目を分割するときの改行. 分割した前の行も元の行と同じ改行にする
+    fn eol(&self, y: usize) -> &'static [u8] {
+        match self.buffer.line_ending(y) {
+            b"" => self.default_eol(),
+            eol => eol,
+        }
+    }
+
+    // 変更を適用して、取り消せるように記録する
+    fn change(&mut self, change: Change) {
+        self.apply(&change);
+        self.pending.push(change);
+    }
+
+    fn apply(&mut self, change: &Change) {
+        let (at, bytes) = match change {
+            Change::Insert { at, bytes } | Change::Delete { at, bytes } => (*at, bytes),
+        };
+        let y = self.buffer.byte_to_line(at);
+        let newlines = bytes.iter().filter(|&&b| b == b'\n').count();
+        match change {
+            Change::Insert { .. } => {
+                self.buffer.insert(at, bytes);
+                self.update_highlight(y, newlines, 0);
+            }
+            Change::Delete { .. } => {
+                self.buffer.delete(at, at + bytes.len());
+                self.update_highlight(y, 0, newlines);
+            }
+        }
+    }
+
+    // ここまでの変更を 1 つの取り消し単位として記録する
+    fn commit(&mut self, kind: EditKind, before: (usize, usize)) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let changes = std::mem::take(&mut self.pending);
+        let after = (self.screen.cx(), self.screen.cy());
+        self.history.push(Transaction {
+            kind,
+            changes,
+            before,
+            after,
+        });
+        self.dirty += 1;
+    }
+
+    fn insert(&mut self, at: usize, bytes: Vec<u8>) {
+        self.change(Change::Insert { at, bytes });
+    }
+
+    fn delete(&mut self, start: usize, end: usize) {
+        let bytes = self.buffer.slice(start, end);
+        self.change(Change::Delete { at: start, bytes });
+    }
+
+    // ファイル末尾の次の行に文字を入力したときは、新しい行を追加する
+    fn append_line(&mut self, text: &[u8]) {
+        let mut bytes = vec![];
+        if !self.buffer.ends_with_newline() {
+            bytes.extend_from_slice(self.default_eol());
+        }
+        bytes.extend_from_slice(text);
+        if self.final_newline() {
+            bytes.extend_from_slice(self.default_eol());
+        }
+        self.insert(self.buffer.len(), bytes);
+    }
+
+    fn insert_char(&mut self, c: char) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        let mut bytes = [0; 4];
+        let bytes = c.encode_utf8(&mut bytes).as_bytes();
+        if cy >= self.buffer.line_count() {
+            self.append_line(bytes);
+        } else {
+            let at = self.buffer.line_to_byte(cy) + cx;
+            self.insert(at, bytes.to_vec());
+        }
+        self.screen.set_cx(cx + bytes.len());
+        self.commit(EditKind::InsertChar, (cx, cy));
+    }
+
+    fn insert_newline(&mut self) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        let n = self.buffer.line_count();
+        if cy >= n {
+            let at = self.buffer.len();
+            self.insert(at, self.default_eol().to_vec());
+        } else {
+            let at = self.buffer.line_to_byte(cy) + cx;
+            self.insert(at, self.eol(cy).to_vec());
+        }
+        // 改行のない最終行の後ろで改行しても行は増えない
+        self.screen.set_cy((cy + 1).min(self.buffer.line_count()));
+        self.screen.set_cx(0);
+        self.commit(EditKind::Other, (cx, cy));
+    }
+
+    // 貼り付けた内容をまとめて 1 回で取り消せるように挿入する.
+    // 端末は改行を \r で送ってくるので、カーソルのある行の改行に揃える
+    fn paste(&mut self, text: &[u8]) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        let n = self.buffer.line_count();
+        let eol = if cy >= n {
+            self.default_eol()
+        } else {
+            self.eol(cy)
+        };
+
+        let mut lines = vec![];
+        let mut line = vec![];
+        let mut iter = text.iter().peekable();
+        while let Some(&b) = iter.next() {
+            match b {
+                b'\r' | b'\n' => {
+                    if b == b'\r' && iter.peek() == Some(&&b'\n') {
+                        iter.next();
+                    }
+                    lines.push(std::mem::take(&mut line));
+                }
+                _ => line.push(b),
+            }
+        }
+        let last = line.len();
+        lines.push(line);
+        let bytes = lines.join(eol);
+        if bytes.is_empty() {
+            return;
+        }
+
+        if cy >= n {
+            // ファイル末尾の次の行に貼り付ける
+            let mut buf = vec![];
+            if !self.buffer.ends_with_newline() {
+                buf.extend_from_slice(self.default_eol());
+            }
+            buf.extend_from_slice(&bytes);
+            if self.final_newline() && last > 0 {
+                buf.extend_from_slice(eol);
+            }
+            self.insert(self.buffer.len(), buf);
+        } else {
+            let at = self.buffer.line_to_byte(cy) + cx;
+            self.insert(at, bytes);
+        }
+        if lines.len() == 1 {
+            self.screen.set_cx(cx + last);
+        } else {
+            self.screen
+                .set_cy((cy + lines.len() - 1).min(self.buffer.line_count()));
+            self.screen.set_cx(last);
+        }
+        self.commit(EditKind::Other, (cx, cy));
+    }
+
+    // y 行目の改行を削除して次の行と連結する. 連結した行の改行は後ろの行のものになる
+    fn join_line(&mut self, y: usize) {
+        let start = self.buffer.line_to_byte(y) + self.row(y).size;
+        let end = self.buffer.line_to_byte(y + 1);
+        self.delete(start, end);
+    }
+
+    // カーソルの左側の文字を削除する. 行頭なら前の行と連結する
+    fn delete_char(&mut self) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        if cy >= self.buffer.line_count() || (cx == 0 && cy == 0) {
+            return;
+        }
+
+        if cx > 0 {
+            let prev = self.row(cy).prev_cx(cx);
+            let start = self.buffer.line_to_byte(cy);
+            self.delete(start + prev, start + cx);
+            self.screen.set_cx(prev);
+        } else {
+            let prev_size = self.row(cy - 1).size;
+            self.join_line(cy - 1);
+            self.screen.set_cy(cy - 1);
+            self.screen.set_cx(prev_size);
+        }
+        self.commit(EditKind::Other, (cx, cy));
+    }
+
+    // カーソル位置の文字を削除する. 行末なら次の行と連結する
+    fn delete_forward_char(&mut self) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        if cy >= self.buffer.line_count() {
+            return;
+        }
+
+        let row = self.row(cy);
+        if cx < row.size {
+            let start = self.buffer.line_to_byte(cy);
+            self.delete(start + cx, start + row.next_cx(cx));
+        } else if cy + 1 < self.buffer.line_count() {
+            self.join_line(cy);
+        }
+        self.commit(EditKind::Other, (cx, cy));
+    }
+
+    fn undo(&mut self) {
+        let tx = match self.history.undo() {
+            Some(tx) => tx,
+            None => return self.screen.set_message("Nothing to undo"),
+        };
+        for change in tx.changes.iter().rev() {
+            self.apply(&change.inverse());
+        }
+        self.dirty += 1;
+        self.screen.set_cx(tx.before.0);
+        self.screen.set_cy(tx.before.1);
+    }
+
+    fn redo(&mut self) {
+        let tx = match self.history.redo() {
+            Some(tx) => tx,
+            None => return self.screen.set_message("Nothing to redo"),
+        };
+        for change in tx.changes.iter() {
+            self.apply(change);
+        }
+        self.dirty += 1;
+        self.screen.set_cx(tx.after.0);
+        self.screen.set_cy(tx.after.1);
+    }
+
+    // 書き込んだバイト数を返す
+    pub fn save(&mut self) -> Result<usize> {
+        // まだ読んでいないファイルの続きを落とさないように、全部読んでから書く
+        self.buffer.wait_index();
+        self.check_read_error();
+        let path = match &self.filepath {
+            Some(path) => path,
+            None => return Err(Error::NoFileName),
+        };
+        if self.read_only {
+            return Err(Error::ReadOnly(path.clone()));
+        }
+        write_atomic(path, &self.buffer).map_err(|err| Error::SaveError(path.clone(), err))?;
+        self.dirty = 0;
+        self.new_file = false;
+        Ok(self.buffer.len())
+    }
+
+    // 異常終了するときに、保存していない変更を元のファイルの隣に書き出す.
+    // 書けなければ一時ディレクトリに書く. 変更がなければ何もしない
+    pub fn write_recovery(&mut self) -> Result<Option<PathBuf>> {
+        if self.dirty == 0 {
+            return Ok(None);
+        }
+        self.buffer.wait_index();
+        let name = self
+            .filepath
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map_or("noname".into(), |name| name.to_string_lossy());
+        let dir = self
+            .filepath
+            .as_ref()
+            .and_then(|path| path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+
+        let (path, mut file) = match create_recovery(dir, &name) {
+            Ok(created) => created,
+            Err(_) => create_recovery(&env::temp_dir(), &name)
+                .map_err(|err| Error::SaveError(env::temp_dir(), err))?,
+        };
+        write_buffer(&mut file, &self.buffer)
+            .and_then(|_| file.sync_all())
+            .map_err(|err| Error::SaveError(path.clone(), err))?;
+        Ok(Some(path))
+    }
+
+    // 開いたファイルを読めなくなったら (他のプロセスが切り詰めたときなど)、
+    // 壊れた内容で上書きしないように読み取り専用にする
+    fn check_read_error(&mut self) {
+        if let Some(err) = self.buffer.take_read_error() {
+            self.read_only = true;
+            self.screen
+                .set_message(format!("Could not read the file: {}", err));
+        }
+    }
+
+    fn refresh_screen(&mut self) -> Result<()> {
+        self.buffer.poll_index();
+        self.check_read_error();
+
+        // 表示する行までハイライトの状態を計算しておく
+        self.screen.scroll(&self.buffer);
+        self.ensure_hl_states(self.screen.row_off() + self.screen.rows());
+
+        let filename = self.filepath.as_ref().map(|p| p.to_string_lossy());
+        let status = Status {
+            filename: filename.as_deref(),
+            filetype: self.filetype(),
+            dirty: self.dirty > 0,
+            indexing: self.buffer.index_progress(),
+            new_file: self.new_file,
+            read_only: self.read_only,
+        };
+
+        let (buffer, tab_stop) = (&self.buffer, self.screen.tab_stop());
+        let (syntax, hl_states, search_match) = (self.syntax, &self.hl_states, self.search_match);
+        let selection = self.selection();
+        let row = |y: usize| {
+            let mut row = EditorRow::new(buffer.line(y), tab_stop);
+            let start = match y {
+                0 => HlState::Normal,
+                _ => hl_states.get(y - 1).copied().unwrap_or(HlState::Normal),
+            };
+            row.update_highlight(syntax, start);
+            if let Some((my, start, end)) = search_match {
+                if my == y {
+                    let (start, end) = (row.cx_to_ri(start), row.cx_to_ri(end));
+                    for hl in row.hl[start..end].iter_mut() {
+                        *hl = Highlight::Match;
+                    }
+                }
+            }
+            if let Some(((sy, sx), (ey, ex))) = selection {
+                if sy <= y && y <= ey {
+                    let start = if y == sy { sx } else { 0 };
+                    let end = if y == ey { ex } else { row.size };
+                    let (start, end) = (row.cx_to_ri(start), row.cx_to_ri(end));
+                    for hl in row.hl[start..end].iter_mut() {
+                        *hl = Highlight::Selection;
+                    }
+                }
+            }
+            row
+        };
+        self.screen.refresh(buffer, row, &status)
+    }
+
+    // 選択している範囲の ((開始行, 開始位置), (終了行, 終了位置))
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let (ax, ay) = self.selection?;
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        if (ay, ax) <= (cy, cx) {
+            Some(((ay, ax), (cy, cx)))
+        } else {
+            Some(((cy, cx), (ay, ax)))
+        }
+    }
+
+    // 選択している範囲のバイト位置 (開始, 終了). 何も選択していなければ None
+    fn selected_range(&self) -> Option<(usize, usize)> {
+        let ((sy, sx), (ey, ex)) = self.selection()?;
+        let (start, end) = (self.pos_to_byte(sx, sy), self.pos_to_byte(ex, ey));
+        if start < end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+
+    // 画面上の位置 (cx, cy) のバイト位置. ファイル末尾の次の行はファイルの終わり
+    fn pos_to_byte(&self, cx: usize, cy: usize) -> usize {
+        if cy < self.buffer.line_count() {
+            self.buffer.line_to_byte(cy) + cx
+        } else {
+            self.buffer.len()
+        }
+    }
+
+    // 選択していた範囲を削除して、カーソルを範囲の先頭に置く
+    fn delete_range(&mut self, start: usize, end: usize) {
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        let y = self.buffer.byte_to_line(start);
+        let x = start - self.buffer.line_to_byte(y);
+        self.delete(start, end);
+        self.screen.set_cy(y);
+        self.screen.set_cx(x);
+        self.commit(EditKind::Other, (cx, cy));
+    }
+
+    // 選択していればその範囲を、していなければ 1 文字を削除する
+    fn delete_selected(&mut self, range: Option<(usize, usize)>, forward: bool) {
+        match range {
+            Some((start, end)) => self.delete_range(start, end),
+            None if forward => self.delete_forward_char(),
+            None => self.delete_char(),
+        }
+    }
+
+    fn copy(&mut self, range: Option<(usize, usize)>) {
+        match range {
+            Some((start, end)) => {
+                self.clipboard = self.buffer.slice(start, end);
+                self.screen
+                    .set_message(format!("{} bytes copied", self.clipboard.len()));
+            }
+            None => self.screen.set_message("Nothing selected"),
+        }
+    }
+
+    // クリックでカーソルを移動し、ドラッグで選択する
+    fn mouse(&mut self, button: MouseButton, x: usize, y: usize, kind: MouseKind) {
+        match (button, kind) {
+            (MouseButton::WheelUp, _) => self.screen.scroll_lines(true, WHEEL_LINES, &self.buffer),
+            (MouseButton::WheelDown, _) => {
+                self.screen.scroll_lines(false, WHEEL_LINES, &self.buffer)
+            }
+            (MouseButton::Left, MouseKind::Press) | (MouseButton::Left, MouseKind::Drag) => {
+                let (cx, cy) = match self.screen.cursor_at(x, y, &self.buffer) {
+                    Some(pos) => pos,
+                    None => return,
+                };
+                self.screen.set_cx(cx);
+                self.screen.set_cy(cy);
+                if kind == MouseKind::Press {
+                    self.selection = Some((cx, cy));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // シェルに戻る. 止まっている間に端末の大きさが変わっているかもしれないので、
+    // 再開したら大きさを調べ直してすべて描き直す
+    fn suspend(&mut self) -> Result<()> {
+        self.screen.restore()?;
+        input::suspend()?;
+        self.screen.setup()?;
+        self.screen.resize(term_size::dimensions(), &mut self.input)
     }
 
     pub fn edit(&mut self) -> Result<()> {
-        self.screen.refresh(self.buf_rows, &mut self.rows)?;
+        // 開いたときの警告があればそちらを表示する
+        if self.screen.message().is_none() {
+            self.screen.set_message(HELP_MESSAGE);
+        }
+
+        self.screen.setup()?;
+        let result = self.edit_loop();
+        // エラーで終わるときも端末のモードは戻す
+        self.screen.restore()?;
+        result
+    }
 
+    fn edit_loop(&mut self) -> Result<()> {
         loop {
-            self.screen.refresh(self.buf_rows, &mut self.rows)?;
-            if let Some(seq) = self.input.next() {
+            self.refresh_screen()?;
+            if let Some(seq) = self.next_key() {
                 let ok = self.process_keypress(seq?)?;
                 if !ok {
                     self.screen.clear()?;
@@ -84,18 +679,228 @@ where
         Ok(())
     }
 
+    // メッセージバーで文字列を入力させる. Esc で中断したら None を返す.
+    // callback は入力が変わるか矢印キーが押されるたびに呼ばれる
+    fn prompt<F>(&mut self, prompt: &str, help: &str, mut callback: F) -> Result<Option<String>>
+    where
+        F: FnMut(&mut Self, &str, &InputSeq),
+    {
+        use KeySeq::*;
+
+        let mut input = String::new();
+        loop {
+            self.screen
+                .set_message(format!("{}{}{}", prompt, input, help));
+            self.refresh_screen()?;
+
+            let seq = match self.next_key() {
+                Some(seq) => seq?,
+                None => return Ok(None),
+            };
+            match seq {
+                InputSeq {
+                    key: Key(b'm'),
+                    ctrl: true,
+                    ..
+                }
+                | InputSeq { key: Enter, .. }
+                    if !input.is_empty() =>
+                {
+                    self.screen.set_message("");
+                    return Ok(Some(input));
+                }
+                InputSeq { key: Key(0x1b), .. } => {
+                    self.screen.set_message("");
+                    return Ok(None);
+                }
+                InputSeq {
+                    key: Key(b'h'),
+                    ctrl: true,
+                    ..
+                }
+                | InputSeq {
+                    key: Key(0x7f),
+                    ctrl: true,
+                    ..
+                }
+                | InputSeq { key: Backspace, .. } => {
+                    input.pop();
+                }
+                InputSeq {
+                    key: Key(b),
+                    ctrl: false,
+                    ..
+                } => input.push(b as char),
+                InputSeq { key: Char(c), .. } => input.push(c),
+                // 改行は入力できないので空白にする
+                InputSeq {
+                    key: Paste(ref buf),
+                    ..
+                } => input.extend(String::from_utf8_lossy(buf).chars().map(|c| {
+                    if c.is_control() {
+                        ' '
+                    } else {
+                        c
+                    }
+                })),
+                InputSeq {
+                    key: Up | Down | Left | Right,
+                    ..
+                } => {}
+                InputSeq {
+                    key: Resize(size), ..
+                } => {
+                    self.screen.resize(size, &mut self.input)?;
+                    continue;
+                }
+                // タイムアウトなどで何も入力されていない
+                _ => continue,
+            }
+            callback(self, &input, &seq);
+        }
+    }
+
+    fn find(&mut self) -> Result<()> {
+        use KeySeq::*;
+
+        let (cx, cy) = (self.screen.cx(), self.screen.cy());
+        let (row_off, col_off) = (self.screen.row_off(), self.screen.col_off());
+
+        let mut last_match: Option<(usize, usize)> = None;
+        let callback = |editor: &mut Self, query: &str, seq: &InputSeq| {
+            let query = query.as_bytes();
+            let found = match (&seq.key, last_match) {
+                (Right, Some((x, y))) | (Down, Some((x, y))) => {
+                    find_forward(&editor.buffer, query, x + 1, y)
+                }
+                (Left, Some((x, y))) | (Up, Some((x, y))) => {
+                    find_backward(&editor.buffer, query, x, y)
+                }
+                (Right, None) | (Down, None) | (Left, None) | (Up, None) => return,
+                // 入力が変わったら元の位置から探し直す
+                _ => find_forward(&editor.buffer, query, cx, cy),
+            };
+
+            last_match = found;
+            editor.search_match = found.map(|(x, y)| (y, x, x + query.len()));
+            match found {
+                Some((x, y)) => {
+                    editor.screen.set_cy(y);
+                    editor.screen.set_cx(x);
+                    editor.screen.set_row_off(y);
+                }
+                None => {
+                    editor.screen.set_cy(cy);
+                    editor.screen.set_cx(cx);
+                    editor.screen.set_row_off(row_off);
+                    editor.screen.set_col_off(col_off);
+                }
+            }
+        };
+
+        let query = self.prompt("Search: ", " (Use ESC/Arrows/Enter)", callback)?;
+        self.search_match = None;
+        if query.is_none() {
+            self.screen.set_cy(cy);
+            self.screen.set_cx(cx);
+            self.screen.set_row_off(row_off);
+            self.screen.set_col_off(col_off);
+        }
+
+        Ok(())
+    }
+
     fn process_keypress(&mut self, seq: InputSeq) -> Result<bool> {
         use KeySeq::*;
+
+        // キーを離したことには反応しない
+        if seq.release {
+            return Ok(true);
+        }
+
+        if seq.ctrl && seq.key == Key(b'q') {
+            if self.dirty > 0 && self.quit_times > 0 {
+                self.screen.set_message(format!(
+                    "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                    self.quit_times
+                ));
+                self.quit_times -= 1;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+        // Ctrl-Q 以外のキーが押されたらやり直し
+        self.quit_times = QUIT_TIMES;
+
+        // 文字の入力が続く間だけ 1 つの取り消し単位にまとめる
+        if !matches!(
+            seq,
+            InputSeq {
+                key: Key(_) | Char(_) | Keypad(_) | Resize(_) | Unidentified,
+                ctrl: false,
+                ..
+            }
+        ) {
+            self.history.seal();
+        }
+
+        // マウス以外のキーが押されたら選択をやめる. 選択していた範囲はこのキーの削除やコピーに使う
+        let selected = self.selected_range();
+        if !matches!(seq.key, Mouse { .. } | Resize(_) | Unidentified) {
+            self.selection = None;
+        }
+
+        // カーソルのある行の終わりがまだ分からなければ、編集する前に少しだけ待つ.
+        // 間に合わなければキーを無視する
+        if is_edit_key(&seq) && !self.buffer.wait_line(self.screen.cy(), INDEX_WAIT) {
+            self.screen.set_message("Still reading the file, try again");
+            return Ok(true);
+        }
+
+        if self.read_only && is_edit_key(&seq) {
+            self.screen.set_message("File is read-only");
+            return Ok(true);
+        }
+
         match seq {
             InputSeq {
-                key, ctrl: true, ..
+                key: key @ Key(_),
+                ctrl: true,
+                ..
             } => match key {
-                Key(b'q') => return Ok(false),
+                Key(b's') => {
+                    let message = match self.save() {
+                        Ok(size) => format!("{} bytes written to disk", size),
+                        Err(err) => format!("{}", err),
+                    };
+                    self.screen.set_message(message);
+                }
+                Key(b'f') => self.find()?,
+                Key(b'u') => self.undo(),
+                Key(b'y') => self.redo(),
+                Key(b'z') => self.suspend()?,
+                Key(b'l') => self.screen.redraw_all(),
+                Key(b'c') => self.copy(selected),
+                Key(b'v') => self.paste(&self.clipboard.clone()),
+                Key(b'm') | Key(b'j') => self.insert_newline(), // Enter
+                Key(b'h') | Key(0x7f) => self.delete_selected(selected, false), // Backspace
+                Key(b'i') => self.insert_char('\t'),            // Tab
                 _ => {}
             },
+            // Alt を押しながらの文字の入力は今のところ何もしない
+            InputSeq {
+                key: Key(_) | Char(_),
+                alt: true,
+                ..
+            } => {}
             InputSeq { key, .. } => match key {
                 Home => self.screen.set_cx(0),
-                End => self.screen.set_cx(self.screen.cols() - 1),
+                End => {
+                    let cy = self.screen.cy();
+                    if cy < self.buffer.line_count() {
+                        self.screen.set_cx(self.row(cy).size);
+                    }
+                }
                 PageUp | PageDown => {
                     let mut times = self.screen.rows();
                     loop {
@@ -104,14 +909,26 @@ where
                             break;
                         }
                         if key == PageUp {
-                            self.screen.move_cursor(Up, self.buf_rows);
+                            self.screen.move_cursor(Up, &self.buffer);
                         } else {
-                            self.screen.move_cursor(Down, self.buf_rows);
+                            self.screen.move_cursor(Down, &self.buffer);
                         }
                     }
                 }
-                Up | Down | Right | Left => self.screen.move_cursor(key, self.buf_rows),
-                Del => {}
+                Up | Down | Right | Left => self.screen.move_cursor(key, &self.buffer),
+                Del => self.delete_selected(selected, true),
+                Backspace => self.delete_selected(selected, false),
+                Enter => self.insert_newline(),
+                Tab => self.insert_char('\t'),
+                Keypad(b) => self.insert_char(b as char),
+                Paste(buf) => self.paste(&buf),
+                Mouse { button, x, y, kind } => self.mouse(button, x, y, kind),
+                KeypadEnter => self.insert_newline(),
+                F(1) => self.screen.set_message(HELP_MESSAGE),
+                Resize(size) => self.screen.resize(size, &mut self.input)?,
+                Key(0x1b) => {}
+                Key(b) => self.insert_char(b as char),
+                Char(c) => self.insert_char(c),
                 _ => {}
             },
         }
@@ -120,12 +937,187 @@ where
     }
 }
 
-fn read_lines<P>(filepath: P) -> Result<io::Lines<io::BufReader<File>>>
+// バッファを書き換えるキーか
+fn is_edit_key(seq: &InputSeq) -> bool {
+    use KeySeq::*;
+
+    if seq.alt {
+        return false;
+    }
+    match seq.key {
+        Key(b) if seq.ctrl => {
+            matches!(b, b'u' | b'y' | b'v' | b'm' | b'j' | b'h' | b'i' | 0x7f)
+        }
+        Key(0x1b) => false,
+        Key(_) | Char(_) | Del | Enter | Tab | Backspace | Keypad(_) | KeypadEnter | Paste(_) => {
+            true
+        }
+        _ => false,
+    }
+}
+
+// 開けなかった理由ごとにエラーを分ける
+fn open_buffer(path: &Path) -> Result<Buffer> {
+    if path.is_dir() {
+        return Err(Error::IsDirectory(path.to_path_buf()));
+    }
+    // 大きなファイルは先頭だけ読んで開き、残りは表示しながら読む
+    Buffer::open(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::NotFound(path.to_path_buf()),
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+        _ => Error::OpenError(path.to_path_buf(), err),
+    })
+}
+
+// 同じディレクトリの一時ファイルに書き出してから rename することで、
+// 書き込み途中で落ちても元のファイルが壊れないようにする
+fn write_atomic(path: &Path, buffer: &Buffer) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a file path",
+            ))
+        }
+    };
+    let perm = fs::metadata(path).ok().map(|m| m.permissions());
+    // 元のファイルより広い権限では作らない. 新しいファイルは umask に従う
+    let mode = perm.as_ref().map_or(0o666, |perm| perm.mode() & 0o777);
+    let tmp_name = |n| format!(".{}.kirocode-{}-{}.tmp", name, process::id(), n);
+    let (tmp, mut file) = create_new(dir, tmp_name, mode)?;
+
+    let result = (|| {
+        // 中身を書く前に元のファイルと同じ権限にする
+        if let Some(perm) = perm {
+            file.set_permissions(perm)?;
+        }
+        write_buffer(&mut file, buffer)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// バッファ全体をコピーせずに、まとめながら順に書き出す
+fn write_buffer(file: &mut File, buffer: &Buffer) -> io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    buffer.write_to(&mut writer)?;
+    writer.flush()
+}
+
+// 復旧用のファイルを新しく作る. 既にあるファイルやシンボリックリンクは開かず、
+// 自分だけが読み書きできる権限にする
+fn create_recovery(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    let name = |n| format!("{}.kirocode-{}-{}.recovery", name, process::id(), n);
+    create_new(dir, name, 0o600)
+}
+
+// dir に name(n) のファイルを mode で新しく作る. 既にあるファイルやシンボリックリンクは
+// 開かずに、次の n の名前を試す
+fn create_new<F>(dir: &Path, name: F, mode: u32) -> io::Result<(PathBuf, File)>
 where
-    P: AsRef<Path>,
+    F: Fn(usize) -> String,
 {
-    let file = File::open(filepath)?;
-    Ok(io::BufReader::new(file).lines())
+    let mut last_err = None;
+    for n in 0..NAME_TRIES {
+        let path = dir.join(name(n));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => last_err = Some(err),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+// 行の中で query が現れる位置
+fn match_positions(buf: &[u8], query: &[u8]) -> Vec<usize> {
+    if query.is_empty() || buf.len() < query.len() {
+        return vec![];
+    }
+    buf.windows(query.len())
+        .enumerate()
+        .filter(|(_, w)| *w == query)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// (x, y) 以降で最初に query が現れる位置. 末尾まで見つからなければ先頭に戻って探す
+fn find_forward(buffer: &Buffer, query: &[u8], x: usize, y: usize) -> Option<(usize, usize)> {
+    let n = buffer.line_count();
+    if n == 0 {
+        return None;
+    }
+    for i in 0..=n {
+        let y = (y + i) % n;
+        let found = match_positions(&buffer.line(y), query)
+            .into_iter()
+            .find(|&pos| match i {
+                0 => pos >= x,
+                _ if i == n => pos < x,
+                _ => true,
+            });
+        if let Some(pos) = found {
+            return Some((pos, y));
+        }
+    }
+    None
+}
+
+// (x, y) より前で最後に query が現れる位置. 先頭まで見つからなければ末尾に戻って探す
+fn find_backward(buffer: &Buffer, query: &[u8], x: usize, y: usize) -> Option<(usize, usize)> {
+    let n = buffer.line_count();
+    if n == 0 {
+        return None;
+    }
+    for i in 0..=n {
+        let y = (y + n * 2 - i) % n;
+        let found = match_positions(&buffer.line(y), query)
+            .into_iter()
+            .rev()
+            .find(|&pos| match i {
+                0 => pos < x,
+                _ if i == n => pos >= x,
+                _ => true,
+            });
+        if let Some(pos) = found {
+            return Some((pos, y));
+        }
+    }
+    None
+}
+
+fn detect_line_ending(buffer: &Buffer) -> LineEnding {
+    // 改行のない最終行は数えない. 大きなファイルは先頭の行だけ見る
+    let (mut lf, mut crlf) = (0, 0);
+    for y in 0..buffer.line_count().min(DETECT_LINES) {
+        match buffer.line_ending(y) {
+            b"\r\n" => crlf += 1,
+            b"\n" => lf += 1,
+            _ => {}
+        }
+    }
+    if crlf == 0 {
+        LineEnding::Lf
+    } else if lf == 0 {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Mixed
+    }
 }
 
 #[cfg(test)]
@@ -136,37 +1128,12 @@ mod tests {
     use crate::input::{DummyInputSequences, KeySeq};
     use KeySeq::*;
 
-    #[test]
-    fn test_read_lines() {
-        let lines = read_lines("");
-        if let Err(err) = lines {
-            match err {
-                Error::IoError(_) => {}
-                _ => unreachable!(),
-            }
-        }
-
-        if let Ok(lines) = read_lines("./test.txt") {
-            let mut cnt = 0;
-            for line in lines {
-                cnt += 1;
-                if let Ok(ip) = line {
-                    assert_eq!(ip, "kirocode test file.");
-                }
-            }
-            assert_eq!(cnt, 1);
-        } else {
-            unreachable!();
-        }
-    }
-
     #[test]
     fn test_editor_new() {
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let e = Editor::new(i, o).unwrap();
-        assert_eq!(e.buf_rows, 0);
-        assert_eq!(e.rows.len(), 0);
+        assert_eq!(e.buffer.line_count(), 0);
     }
 
     #[test]
@@ -174,45 +1141,936 @@ mod tests {
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let e = Editor::open("", i, o).unwrap();
-        assert_eq!(e.buf_rows, 0);
-        assert_eq!(e.rows.len(), 0);
+        assert_eq!(e.buffer.line_count(), 0);
 
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let e = Editor::open("./test.txt", i, o).unwrap();
-        assert_eq!(e.buf_rows, 1);
-        assert_eq!(e.rows.len(), 1);
+        assert_eq!(e.buffer.line_count(), 1);
+        assert_eq!(e.row(0).size, 19);
+        assert_eq!(e.row(0).buf, b"kirocode test file.");
+    }
+
+    #[test]
+    fn test_process_keypress() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::new(i, o).unwrap();
+
+        let ret = e.process_keypress(InputSeq::new(Key(b'a')));
+        assert!(ret.unwrap());
+
+        // quit: 未保存の変更があるので QUIT_TIMES 回は確認される
+        for _ in 0..QUIT_TIMES {
+            let ret = e.process_keypress(InputSeq::ctrl(Key(b'q')));
+            assert!(ret.unwrap());
+        }
+        let ret = e.process_keypress(InputSeq::ctrl(Key(b'q')));
+        assert!(!ret.unwrap());
+
+        // TODO: cols(), rows() を使って move_cursor をテスト
+    }
+
+    fn edit_with_keys(rows: &[&[u8]], keys: Vec<InputSeq>) -> Editor<DummyInputSequences, Vec<u8>> {
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::new(i, o).unwrap();
+        e.buffer = buffer(rows);
+        e.edit().unwrap();
+        e
+    }
+
+    fn buffer(rows: &[&[u8]]) -> Buffer {
+        let mut buf = vec![];
+        for row in rows {
+            buf.extend_from_slice(row);
+            buf.push(b'\n');
+        }
+        Buffer::from(buf)
+    }
+
+    fn bufs<I, W>(e: &Editor<I, W>) -> Vec<Vec<u8>>
+    where
+        I: Iterator<Item = Result<InputSeq>>,
+        W: Write,
+    {
+        e.buffer.lines().collect()
+    }
+
+    #[test]
+    fn test_insert_char() {
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Key(b'b')),
+            // Esc だけでは何も入力しない
+            InputSeq::new(Key(0x1b)),
+            InputSeq::new(Key(b'c')),
+        ];
+        let e = edit_with_keys(&[], keys);
+        assert_eq!(bufs(&e), vec![b"abc"]);
+        assert_eq!(e.row(0).size, 3);
+        assert_eq!(e.buffer.line_count(), 1);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (3, 0));
+
+        let keys = vec![
+            InputSeq::new(Right),
+            InputSeq::new(Key(b'x')),
+            InputSeq::new(End),
+            InputSeq::new(Key(b'!')),
+        ];
+        let e = edit_with_keys(&[b"abc"], keys);
+        assert_eq!(bufs(&e), vec![b"axbc!"]);
+        assert_eq!(e.row(0).size, 5);
+    }
+
+    #[test]
+    fn test_insert_newline() {
+        // 行の途中で分割
+        let keys = vec![
+            InputSeq::new(Right),
+            InputSeq::new(Right),
+            InputSeq::ctrl(Key(b'm')),
+        ];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![&b"he"[..], &b"llo"[..]]);
+        assert_eq!(e.row(0).size, 2);
+        assert_eq!(e.row(1).size, 3);
+        assert_eq!(e.buffer.line_count(), 2);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 1));
+
+        // 行頭なら空行を挿入
+        let keys = vec![InputSeq::ctrl(Key(b'm'))];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![&b""[..], &b"hello"[..]]);
+
+        // 行末
+        let keys = vec![InputSeq::new(End), InputSeq::ctrl(Key(b'j'))];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![&b"hello"[..], &b""[..]]);
+    }
+
+    #[test]
+    fn test_delete_char() {
+        let keys = vec![InputSeq::new(End), InputSeq::ctrl(Key(b'h'))];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![b"hell"]);
+        assert_eq!(e.row(0).size, 4);
+        assert_eq!(e.screen.cx(), 4);
+
+        // 行頭なら前の行と連結
+        let keys = vec![InputSeq::new(Down), InputSeq::ctrl(Key(0x7f))];
+        let e = edit_with_keys(&[b"hello", b"world"], keys);
+        assert_eq!(bufs(&e), vec![b"helloworld"]);
+        assert_eq!(e.row(0).size, 10);
+        assert_eq!(e.buffer.line_count(), 1);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (5, 0));
+
+        // 先頭では何もしない
+        let keys = vec![InputSeq::ctrl(Key(b'h'))];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![b"hello"]);
+    }
+
+    #[test]
+    fn test_delete_forward_char() {
+        let keys = vec![InputSeq::new(Del)];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![b"ello"]);
+        assert_eq!(e.row(0).size, 4);
+        assert_eq!(e.screen.cx(), 0);
+
+        // 行末なら次の行と連結
+        let keys = vec![InputSeq::new(End), InputSeq::new(Del)];
+        let e = edit_with_keys(&[b"hello", b"world"], keys);
+        assert_eq!(bufs(&e), vec![b"helloworld"]);
+        assert_eq!(e.buffer.line_count(), 1);
 
-        assert_eq!(e.rows[0].size, 19);
-        assert_eq!(e.rows[0].buf, b"kirocode test file.");
+        // 最終行の行末では何もしない
+        let keys = vec![InputSeq::new(End), InputSeq::new(Del)];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(bufs(&e), vec![b"hello"]);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kirocode-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn test_save() {
+        let path = temp_path("save.txt");
+        let _ = fs::remove_file(&path);
+
+        let keys = vec![
+            InputSeq::new(Key(b'h')),
+            InputSeq::new(Key(b'i')),
+            InputSeq::ctrl(Key(b's')),
+        ];
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.edit().unwrap();
+        assert_eq!(e.screen.message(), Some("3 bytes written to disk"));
+        assert_eq!(fs::read(&path).unwrap(), b"hi\n");
+
+        // 一時ファイルが残っていない
+        let dir = path.parent().unwrap();
+        for entry in fs::read_dir(dir).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(!name.to_string_lossy().ends_with(".tmp"));
+        }
+    }
+
+    #[test]
+    fn test_save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("perm.txt");
+        fs::write(&path, b"abc\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let i = DummyInputSequences(vec![InputSeq::new(Del)]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.edit().unwrap();
+        assert_eq!(e.save().unwrap(), 3);
+        assert_eq!(fs::read(&path).unwrap(), b"bc\n");
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
     }
 
     #[test]
-    fn test_append_row() {
+    fn test_save_existing_tmp() {
+        let path = temp_path("save-existing.txt");
+        fs::write(&path, b"abc\n").unwrap();
+        let target = temp_path("save-target.txt");
+        fs::write(&target, b"target\n").unwrap();
+
+        // 一時ファイルの名前に、既にシンボリックリンクとファイルがある
+        let name = |n| {
+            temp_path(&format!(
+                ".save-existing.txt.kirocode-{}-{}.tmp",
+                process::id(),
+                n
+            ))
+        };
+        std::os::unix::fs::symlink(&target, name(0)).unwrap();
+        fs::write(name(1), b"old\n").unwrap();
+
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.insert_char('x');
+        assert_eq!(e.save().unwrap(), 5);
+        assert_eq!(fs::read(&path).unwrap(), b"xabc\n");
+
+        // どちらも上書きせず、使った一時ファイルは残らない
+        assert_eq!(fs::read(&target).unwrap(), b"target\n");
+        assert_eq!(fs::read(name(1)).unwrap(), b"old\n");
+        assert!(fs::symlink_metadata(name(2)).is_err());
+
+        for n in 0..2 {
+            fs::remove_file(name(n)).unwrap();
+        }
+        fs::remove_file(&target).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_error() {
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let mut e = Editor::new(i, o).unwrap();
+        match e.save() {
+            Err(Error::NoFileName) => {}
+            _ => unreachable!(),
+        }
 
-        e.append_row(b"kirocode".to_vec(), 8);
-        assert_eq!(e.rows.len(), 1);
-        assert_eq!(e.rows[0].size, 8);
-        assert_eq!(e.rows[0].buf, b"kirocode");
-        assert_eq!(e.buf_rows, 1);
+        let i = DummyInputSequences(vec![InputSeq::ctrl(Key(b's'))]);
+        let o: Vec<u8> = vec![];
+        let path = temp_path("no-such-dir/file.txt");
+        let mut e = Editor::open(&path, i, o).unwrap();
+        match e.save() {
+            Err(Error::SaveError(p, _)) => assert_eq!(p, path),
+            _ => unreachable!(),
+        }
+
+        // エラーでもセッションは終了しない
+        e.edit().unwrap();
+        assert!(e.screen.message().unwrap().starts_with("Can't save"));
     }
 
     #[test]
-    fn test_process_keypress() {
+    fn test_paste() {
+        let keys = vec![
+            InputSeq::new(Right),
+            InputSeq::new(Paste(b"x\ry\r\nz".to_vec())),
+            InputSeq::new(Key(b'!')),
+        ];
+        let mut e = edit_with_keys(&[b"ab"], keys);
+        assert_eq!(bufs(&e), vec![&b"ax"[..], b"y", b"z!b"]);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (2, 2));
+
+        // 貼り付けた内容は 1 回で取り消せる
+        e.undo();
+        e.undo();
+        assert_eq!(bufs(&e), vec![b"ab"]);
+
+        // ファイル末尾の次の行に貼り付ける
+        let keys = vec![
+            InputSeq::new(Down),
+            InputSeq::new(Paste(b"c\rd\r".to_vec())),
+        ];
+        let e = edit_with_keys(&[b"ab"], keys);
+        assert_eq!(bufs(&e), vec![&b"ab"[..], b"c", b"d"]);
+        assert_eq!(e.buffer.to_vec(), b"ab\nc\nd\n");
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 3));
+    }
+
+    fn mouse(button: MouseButton, x: usize, y: usize, kind: MouseKind) -> InputSeq {
+        InputSeq::new(Mouse { button, x, y, kind })
+    }
+
+    #[test]
+    fn test_mouse() {
+        use MouseKind::*;
+
+        let rows: Vec<&[u8]> = vec![b"abcdef"; 30];
+        let keys = vec![
+            mouse(MouseButton::Left, 2, 1, Press),
+            mouse(MouseButton::Left, 4, 3, Drag),
+            mouse(MouseButton::Left, 4, 3, Release),
+        ];
+        let e = edit_with_keys(&rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (4, 3));
+        assert_eq!(e.selection(), Some(((1, 2), (3, 4))));
+
+        // 前にドラッグしても開始位置は先になる
+        let keys = vec![
+            mouse(MouseButton::Left, 4, 3, Press),
+            mouse(MouseButton::Left, 1, 0, Drag),
+        ];
+        let e = edit_with_keys(&rows, keys);
+        assert_eq!(e.selection(), Some(((0, 1), (3, 4))));
+
+        // キーを押したら選択をやめる
+        let keys = vec![
+            mouse(MouseButton::Left, 4, 3, Press),
+            mouse(MouseButton::Left, 1, 0, Drag),
+            InputSeq::new(Right),
+        ];
+        let e = edit_with_keys(&rows, keys);
+        assert_eq!(e.selection(), None);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (2, 0));
+
+        // 選択した範囲を削除する
+        let keys = vec![
+            mouse(MouseButton::Left, 4, 1, Press),
+            mouse(MouseButton::Left, 1, 0, Drag),
+            InputSeq::new(Backspace),
+        ];
+        let e = edit_with_keys(&[b"abcdef", b"ghijkl"], keys);
+        assert_eq!(bufs(&e), vec![b"akl" as &[u8]]);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (1, 0));
+
+        // コピーして貼り付ける. コピーしても選択した内容は消えない
+        let keys = vec![
+            mouse(MouseButton::Left, 1, 0, Press),
+            mouse(MouseButton::Left, 3, 0, Drag),
+            InputSeq::ctrl(Key(b'c')),
+            InputSeq::new(End),
+            InputSeq::ctrl(Key(b'v')),
+        ];
+        let e = edit_with_keys(&[b"abcdef"], keys);
+        assert_eq!(bufs(&e), vec![b"abcdefbc" as &[u8]]);
+
+        // ホイールでスクロールしてから、ずれた位置をクリックする
+        let keys = vec![
+            mouse(MouseButton::WheelDown, 0, 0, Press),
+            mouse(MouseButton::WheelDown, 0, 0, Press),
+            mouse(MouseButton::Left, 1, 2, Press),
+        ];
+        let e = edit_with_keys(&rows, keys);
+        assert_eq!(e.screen.row_off(), 6);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (1, 8));
+    }
+
+    #[test]
+    fn test_detect_kitty_keyboard() {
+        // 問い合わせの答えを待つ間に押したキーも入力する
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(KeyboardFlags(0)),
+            InputSeq::new(Key(b'b')),
+            InputSeq::new(DeviceAttributes),
+            InputSeq::new(Key(b'c')),
+        ];
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::new(i, o).unwrap();
+        assert!(e.detect_kitty_keyboard().unwrap());
+        e.edit().unwrap();
+        assert_eq!(bufs(&e), vec![b"abc" as &[u8]]);
+    }
+
+    #[test]
+    fn test_kitty_keys() {
+        let mut release = InputSeq::new(Key(b'b'));
+        release.release = true;
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            release,
+            InputSeq::new(Tab),
+            InputSeq::new(Enter),
+            InputSeq::new(Key(b'c')),
+            InputSeq::new(Backspace),
+        ];
+        let e = edit_with_keys(&[], keys);
+        assert_eq!(bufs(&e), vec![&b"a\t"[..], b""]);
+    }
+
+    #[test]
+    fn test_keypad() {
+        let keys = vec![
+            InputSeq::new(Keypad(b'1')),
+            InputSeq::new(Keypad(b'+')),
+            InputSeq::new(KeypadEnter),
+            InputSeq::new(F(1)),
+        ];
+        let e = edit_with_keys(&[], keys);
+        assert_eq!(bufs(&e), vec![&b"1+"[..], b""]);
+        assert_eq!(e.screen.message(), Some(HELP_MESSAGE));
+    }
+
+    #[test]
+    fn test_resize() {
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Resize(Some((30, 8)))),
+            InputSeq::new(Key(b'b')),
+        ];
+        let rows: Vec<&[u8]> = vec![b"x"; 50];
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::new(i, o).unwrap();
+        e.buffer = buffer(&rows);
+        e.screen.set_cy(40);
+        e.edit().unwrap();
+
+        assert_eq!((e.screen.cols(), e.screen.rows()), (30, 6));
+        // カーソルが見える位置までスクロールする
+        assert_eq!(e.screen.row_off(), 35);
+        // 大きさが変わっても文字の入力はまとめて取り消せる
+        e.undo();
+        assert_eq!(e.buffer.line(40), b"x");
+    }
+
+    #[test]
+    fn test_suspend() {
+        // raw モードにしていないので止まらずに描き直すだけ
+        let keys = vec![InputSeq::new(Key(b'a')), InputSeq::ctrl(Key(b'z'))];
+        let e = edit_with_keys(&[b"xy"], keys);
+        assert_eq!(bufs(&e), vec![b"axy" as &[u8]]);
+        assert_eq!(e.dirty(), 1);
+
+        // 止まる前に元の画面に戻り、再開したら代替画面に入り直して全体を描き直す
+        let find = |buf: &[u8], pat: &[u8]| buf.windows(pat.len()).position(|w| w == pat);
+        let output = e.screen.output();
+        let restored = find(output, b"\x1b[?1049l").unwrap();
+        let output = &output[restored..];
+        let resumed = find(output, b"\x1b[?1049h").unwrap();
+        assert!(output[resumed..].starts_with(
+            b"\x1b[?1049h\x1b[?2004h\x1b[?1002h\x1b[?1006h\x1b[?25l\x1b[2J\x1b[1;1H\x1b[Kaxy\x1b[2;1H"
+        ));
+    }
+
+    #[test]
+    fn test_write_recovery() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = temp_path("recover.txt");
+        fs::write(&path, b"abc\n").unwrap();
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        assert_eq!(e.write_recovery().unwrap(), None);
+
+        e.insert_char('x');
+        let recovery = e.write_recovery().unwrap().unwrap();
+        assert_eq!(recovery.parent(), path.parent());
+        assert!(recovery.to_string_lossy().contains("recover.txt.kirocode-"));
+        assert_eq!(fs::read(&recovery).unwrap(), b"xabc\n");
+        let mode = fs::metadata(&recovery).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // 元のファイルは変えない
+        assert_eq!(fs::read(&path).unwrap(), b"abc\n");
+        fs::remove_file(&recovery).unwrap();
+    }
+
+    #[test]
+    fn test_write_recovery_existing() {
+        let path = temp_path("recover-existing.txt");
+        fs::write(&path, b"abc\n").unwrap();
+        let target = temp_path("recover-target.txt");
+        fs::write(&target, b"target\n").unwrap();
+
+        // 使おうとする名前に、既にシンボリックリンクとファイルがある
+        let name = |n| {
+            temp_path(&format!(
+                "recover-existing.txt.kirocode-{}-{}.recovery",
+                process::id(),
+                n
+            ))
+        };
+        std::os::unix::fs::symlink(&target, name(0)).unwrap();
+        fs::write(name(1), b"old\n").unwrap();
+
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.insert_char('x');
+        let recovery = e.write_recovery().unwrap().unwrap();
+        assert_eq!(recovery, name(2));
+        assert_eq!(fs::read(&recovery).unwrap(), b"xabc\n");
+
+        // どちらも上書きしない
+        assert_eq!(fs::read(&target).unwrap(), b"target\n");
+        assert_eq!(fs::read(name(1)).unwrap(), b"old\n");
+
+        for n in 0..3 {
+            fs::remove_file(name(n)).unwrap();
+        }
+        fs::remove_file(&target).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_error() {
+        // ないファイルは新しいファイルとして開く
+        let path = temp_path("new.txt");
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        assert!(e.new_file);
+        assert!(!e.read_only);
+        assert_eq!(e.buffer.line_count(), 0);
+        e.save().unwrap();
+        assert!(!e.new_file);
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_path("dir");
+        fs::create_dir_all(&path).unwrap();
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        match Editor::open(&path, i, o) {
+            Err(Error::IsDirectory(p)) => assert_eq!(p, path),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Right),
+            InputSeq::ctrl(Key(b'm')),
+        ];
+        let path = temp_path("read-only.txt");
+        fs::write(&path, b"abc\n").unwrap();
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.read_only = true;
+        e.edit().unwrap();
+
+        // 書き換えるキーは無視して、移動はできる
+        assert_eq!(bufs(&e), vec![b"abc"]);
+        assert_eq!(e.dirty(), 0);
+        assert_eq!(e.screen.cx(), 1);
+        assert_eq!(e.screen.message(), Some("File is read-only"));
+        match e.save() {
+            Err(Error::ReadOnly(p)) => assert_eq!(p, path),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_dirty() {
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::ctrl(Key(b'm')),
+            InputSeq::ctrl(Key(b'h')),
+            InputSeq::new(Del),
+        ];
+        let e = edit_with_keys(&[b"hello"], keys);
+        assert_eq!(e.dirty(), 4);
+
+        // 何も変更しなければ 0 のまま
+        let keys = vec![InputSeq::new(Right), InputSeq::ctrl(Key(b'h'))];
+        let e = edit_with_keys(&[b"hello"], vec![InputSeq::new(Down)]);
+        assert_eq!(e.dirty(), 0);
+        let e = edit_with_keys(&[], keys);
+        assert_eq!(e.dirty(), 0);
+
+        // 保存したら 0 に戻る
+        let path = temp_path("dirty.txt");
+        let keys = vec![InputSeq::new(Key(b'a')), InputSeq::ctrl(Key(b's'))];
+        let i = DummyInputSequences(keys);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.edit().unwrap();
+        assert_eq!(e.dirty(), 0);
+    }
+
+    #[test]
+    fn test_undo() {
+        let undo = || InputSeq::ctrl(Key(b'u'));
+        let redo = || InputSeq::ctrl(Key(b'y'));
+
+        // 続けて入力した文字はまとめて取り消す
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Key(b'b')),
+            InputSeq::ctrl(Key(b'm')),
+            InputSeq::new(Key(b'c')),
+            undo(),
+        ];
+        let e = edit_with_keys(&[b"xy"], keys);
+        assert_eq!(bufs(&e), vec![b"ab" as &[u8], b"xy"]);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 1));
+
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Key(b'b')),
+            InputSeq::ctrl(Key(b'm')),
+            undo(),
+            undo(),
+        ];
+        let e = edit_with_keys(&[b"xy"], keys);
+        assert_eq!(bufs(&e), vec![b"xy" as &[u8]]);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 0));
+
+        // カーソルを動かしたら別の取り消し単位
+        let keys = vec![
+            InputSeq::new(Key(b'a')),
+            InputSeq::new(Right),
+            InputSeq::new(Left),
+            InputSeq::new(Key(b'b')),
+            undo(),
+        ];
+        let e = edit_with_keys(&[b"xy"], keys);
+        assert_eq!(bufs(&e), vec![b"axy" as &[u8]]);
+
+        // やり直し
+        let keys = vec![InputSeq::new(Key(b'a')), undo(), redo(), redo()];
+        let e = edit_with_keys(&[b"xy"], keys);
+        assert_eq!(bufs(&e), vec![b"axy" as &[u8]]);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (1, 0));
+    }
+
+    #[test]
+    fn test_undo_join() {
+        let undo = || InputSeq::ctrl(Key(b'u'));
+
+        // 行の連結を取り消すと改行の種類も元に戻る
+        let mut e = open_bytes("undo_join.txt", b"ab\ncd\r\n");
+        e.screen.set_cy(1);
+        e.input = DummyInputSequences(vec![InputSeq::ctrl(Key(b'h')), undo()]);
+        e.edit().unwrap();
+        assert_eq!(e.buffer.to_vec(), b"ab\ncd\r\n");
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 1));
+
+        let mut e = open_bytes("undo_join.txt", b"ab\ncd\r\n");
+        e.screen.set_cx(2);
+        e.input = DummyInputSequences(vec![InputSeq::new(Del), undo()]);
+        e.edit().unwrap();
+        assert_eq!(e.buffer.to_vec(), b"ab\ncd\r\n");
+    }
+
+    #[test]
+    fn test_quit_dirty() {
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let mut e = Editor::new(i, o).unwrap();
 
-        let ret = e.process_keypress(InputSeq::new(Key(b'a')));
-        assert_eq!(ret.unwrap(), true);
+        // 変更がなければすぐに終了
+        assert!(!e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap());
 
-        // quit
-        let ret = e.process_keypress(InputSeq::ctrl(Key(b'q')));
-        assert_eq!(ret.unwrap(), false);
+        e.process_keypress(InputSeq::new(Key(b'a'))).unwrap();
+        for n in (1..=QUIT_TIMES).rev() {
+            assert!(e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap());
+            let message = format!("Press Ctrl-Q {} more times to quit.", n);
+            assert!(e.screen.message().unwrap().ends_with(&message));
+        }
+        assert!(!e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap());
 
-        // TODO: cols(), rows() を使って move_cursor をテスト
+        // 途中で他のキーを押したら回数はリセットされる
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::new(i, o).unwrap();
+        e.process_keypress(InputSeq::new(Key(b'a'))).unwrap();
+        e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap();
+        e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap();
+        e.process_keypress(InputSeq::new(Left)).unwrap();
+        assert_eq!(e.quit_times, QUIT_TIMES);
+        for _ in 0..QUIT_TIMES {
+            assert!(e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap());
+        }
+        assert!(!e.process_keypress(InputSeq::ctrl(Key(b'q'))).unwrap());
+    }
+
+    #[test]
+    fn test_tab_stop() {
+        let keys = vec![InputSeq::ctrl(Key(b'i')), InputSeq::new(Key(b'x'))];
+        let mut e = edit_with_keys(&[], keys);
+        assert_eq!(bufs(&e), vec![b"\tx"]);
+        assert_eq!(e.row(0).render, b"        x");
+
+        e.set_tab_stop(2);
+        assert_eq!(e.row(0).render, b"  x");
+    }
+
+    #[test]
+    fn test_edit_utf8() {
+        let keys = vec![
+            InputSeq::new(Char('あ')),
+            InputSeq::new(Char('い')),
+            InputSeq::new(Key(b'a')),
+            InputSeq::ctrl(Key(b'h')),
+            InputSeq::ctrl(Key(b'h')),
+        ];
+        let e = edit_with_keys(&[], keys);
+        assert_eq!(bufs(&e), vec!["あ".as_bytes()]);
+        assert_eq!(e.screen.cx(), 3);
+
+        // 書記素クラスタ単位で削除する
+        let keys = vec![InputSeq::new(Right), InputSeq::new(Del)];
+        let e = edit_with_keys(&["aか\u{3099}b".as_bytes()], keys);
+        assert_eq!(bufs(&e), vec![b"ab"]);
+        assert_eq!(e.row(0).size, 2);
+    }
+
+    fn open_bytes(name: &str, buf: &[u8]) -> Editor<DummyInputSequences, Vec<u8>> {
+        let path = temp_path(name);
+        fs::write(&path, buf).unwrap();
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        Editor::open(&path, i, o).unwrap()
+    }
+
+    #[test]
+    fn test_highlight_rows() {
+        let mut e = open_bytes("hl.c", b"int a;\nint b;\nint c;\n");
+        assert_eq!(e.filetype(), Some("c"));
+        e.ensure_hl_states(3);
+        assert_eq!(e.hl_states, vec![HlState::Normal; 3]);
+
+        // 複数行コメントを開くと後ろの行まで塗り直す
+        e.insert(0, b"/*".to_vec());
+        assert_eq!(e.hl_states, vec![HlState::BlockComment; 3]);
+
+        e.insert(7, b"*/".to_vec());
+        assert_eq!(e.buffer.line(0), b"/*int a*/;");
+        assert_eq!(e.hl_states, vec![HlState::Normal; 3]);
+
+        // 行が増減したらずらして計算し直す
+        e.insert(0, b"/*\n\n".to_vec());
+        assert_eq!(e.hl_states.len(), 5);
+        assert_eq!(e.hl_states[1], HlState::BlockComment);
+        assert_eq!(e.hl_states[4], HlState::Normal);
+        e.delete(0, 4);
+        assert_eq!(e.hl_states, vec![HlState::Normal; 3]);
+
+        // 計算していない行は表示するときに計算する
+        e.hl_states.truncate(1);
+        e.insert(e.buffer.line_to_byte(2), b"/*".to_vec());
+        assert_eq!(e.hl_states.len(), 1);
+
+        // 拡張子が分からなければハイライトしない
+        let mut e = open_bytes("hl.txt", b"int a;\n");
+        assert_eq!(e.filetype(), None);
+        e.ensure_hl_states(1);
+        assert!(e.hl_states.is_empty());
+    }
+
+    #[test]
+    fn test_find_highlight() {
+        let mut e = open_bytes("find_hl.c", b"abcd\n");
+        let keys = vec![InputSeq::new(Key(b'b')), InputSeq::ctrl(Key(b'm'))];
+        e.input = DummyInputSequences(keys);
+        e.find().unwrap();
+        // 検索が終わったら一致箇所のハイライトは消す
+        assert_eq!(e.search_match, None);
+    }
+
+    #[test]
+    fn test_open_save_round_trip() {
+        let files: Vec<&[u8]> = vec![
+            b"",
+            b"\n",
+            b"\n\n",
+            b"no final newline",
+            b"lf\nlf\n",
+            b"crlf\r\ncrlf\r\n",
+            b"mixed\r\nmixed\nmixed",
+            b"invalid \xff\xfe utf-8\n\xe3\x81\n",
+            b"lone \r cr\r",
+        ];
+        for (n, buf) in files.into_iter().enumerate() {
+            let name = format!("round-trip-{}.txt", n);
+            let mut e = open_bytes(&name, buf);
+            e.save().unwrap();
+            assert_eq!(fs::read(temp_path(&name)).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn test_final_newline_lazy() {
+        // 先頭の断片は改行で終わっているが、ファイルは改行で終わっていない
+        let path = temp_path("final-newline.txt");
+        fs::write(&path, b"a\nb").unwrap();
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut e = Editor::open(&path, i, o).unwrap();
+        e.buffer = Buffer::open_with(&path, 0, 2).unwrap();
+        assert!(e.buffer.is_indexing());
+
+        e.buffer.wait_index();
+        e.screen.set_cy(2);
+        e.insert_char('x');
+        e.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\nx");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_keeps_invalid_utf8() {
+        let e = open_bytes("invalid.txt", b"a\xffb\nok\n");
+        assert_eq!(bufs(&e), vec![&b"a\xffb"[..], &b"ok"[..]]);
+        assert_eq!(e.row(0).render, "a\u{fffd}b".as_bytes());
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let e = open_bytes("lf.txt", b"a\nb\n");
+        assert_eq!(e.line_ending(), LineEnding::Lf);
+        let e = open_bytes("crlf.txt", b"a\r\nb");
+        assert_eq!(e.line_ending(), LineEnding::CrLf);
+        let e = open_bytes("mixed.txt", b"a\r\nb\n");
+        assert_eq!(e.line_ending(), LineEnding::Mixed);
+
+        // 新しい行はファイルの改行に合わせる
+        let mut e = open_bytes("crlf-edit.txt", b"ab\r\ncd");
+        e.process_keypress(InputSeq::new(Right)).unwrap();
+        e.process_keypress(InputSeq::ctrl(Key(b'm'))).unwrap();
+        e.process_keypress(InputSeq::new(Down)).unwrap();
+        e.process_keypress(InputSeq::new(End)).unwrap();
+        e.process_keypress(InputSeq::ctrl(Key(b'm'))).unwrap();
+        e.process_keypress(InputSeq::new(Key(b'e'))).unwrap();
+        assert_eq!(e.buffer.to_vec(), b"a\r\nb\r\ncd\r\ne");
+
+        // 行を連結したら後ろの行の改行になる
+        let mut e = open_bytes("mixed-join.txt", b"a\r\nb\nc\r\n");
+        e.process_keypress(InputSeq::new(End)).unwrap();
+        e.process_keypress(InputSeq::new(Del)).unwrap();
+        assert_eq!(e.buffer.to_vec(), b"ab\nc\r\n");
+    }
+
+    #[test]
+    fn test_find_forward() {
+        let rows = buffer(&[b"abab", b"", b"xab"]);
+        assert_eq!(find_forward(&rows, b"ab", 0, 0), Some((0, 0)));
+        assert_eq!(find_forward(&rows, b"ab", 1, 0), Some((2, 0)));
+        assert_eq!(find_forward(&rows, b"ab", 3, 0), Some((1, 2)));
+        // 末尾まで行ったら先頭に戻る
+        assert_eq!(find_forward(&rows, b"ab", 2, 2), Some((0, 0)));
+        assert_eq!(find_forward(&rows, b"zz", 0, 0), None);
+        assert_eq!(find_forward(&Buffer::new(), b"ab", 0, 0), None);
+
+        // 1 つしかなければ同じ位置に戻ってくる
+        let rows = buffer(&[b"ab"]);
+        assert_eq!(find_forward(&rows, b"ab", 1, 0), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_find_backward() {
+        let rows = buffer(&[b"abab", b"", b"xab"]);
+        assert_eq!(find_backward(&rows, b"ab", 2, 0), Some((0, 0)));
+        assert_eq!(find_backward(&rows, b"ab", 3, 0), Some((2, 0)));
+        assert_eq!(find_backward(&rows, b"ab", 0, 2), Some((2, 0)));
+        // 先頭まで行ったら末尾に戻る
+        assert_eq!(find_backward(&rows, b"ab", 0, 0), Some((1, 2)));
+        assert_eq!(find_backward(&rows, b"zz", 0, 0), None);
+    }
+
+    fn find_keys(query: &str, keys: Vec<InputSeq>) -> Vec<InputSeq> {
+        let mut seqs = vec![InputSeq::ctrl(Key(b'f'))];
+        seqs.extend(query.bytes().map(|b| InputSeq::new(Key(b))));
+        seqs.extend(keys);
+        seqs
+    }
+
+    #[test]
+    fn test_find() {
+        let rows: &[&[u8]] = &[b"hello world", b"foo", b"world peace"];
+        let enter = || InputSeq::ctrl(Key(b'm'));
+
+        let e = edit_with_keys(rows, find_keys("wor", vec![enter()]));
+        assert_eq!((e.screen.cx(), e.screen.cy()), (6, 0));
+
+        // 次の候補
+        let keys = find_keys("wor", vec![InputSeq::new(Down), enter()]);
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 2));
+        assert_eq!(e.screen.row_off(), 2);
+
+        // 末尾まで行ったら先頭に戻る
+        let keys = find_keys(
+            "wor",
+            vec![InputSeq::new(Right), InputSeq::new(Right), enter()],
+        );
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (6, 0));
+
+        // 前の候補
+        let keys = find_keys("wor", vec![InputSeq::new(Up), enter()]);
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 2));
+
+        // カーソルの位置から探す
+        let mut keys = vec![InputSeq::new(Down)];
+        keys.extend(find_keys("wor", vec![enter()]));
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 2));
+
+        // 検索しても文字は入力されない
+        assert_eq!(e.dirty(), 0);
+    }
+
+    #[test]
+    fn test_find_cancel() {
+        let rows: &[&[u8]] = &[b"hello world", b"foo", b"world peace"];
+
+        // Esc で元の位置に戻る
+        let mut keys = vec![InputSeq::new(Right)];
+        keys.extend(find_keys(
+            "wor",
+            vec![InputSeq::new(Down), InputSeq::new(Key(0x1b))],
+        ));
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (1, 0));
+        assert_eq!(e.screen.row_off(), 0);
+
+        // 見つからなければ動かない
+        let keys = find_keys("xyz", vec![InputSeq::ctrl(Key(b'm'))]);
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (0, 0));
+
+        // 途中で見つからなくなったら元の位置に戻る
+        let mut keys = vec![InputSeq::new(Right)];
+        keys.extend(find_keys("wx", vec![InputSeq::ctrl(Key(b'm'))]));
+        let e = edit_with_keys(rows, keys);
+        assert_eq!((e.screen.cx(), e.screen.cy()), (1, 0));
     }
 }
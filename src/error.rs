@@ -1,41 +1,103 @@
-use std::convert::From;
+use std::error;
 use std::fmt;
 use std::io;
-use std::num;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum Error {
-    IoError(io::Error),
-    ParseIntError(num::ParseIntError),
-    InputReadByteError,
-    InputNotFoundEscapeError,
+    // (何をしようとしていたか, 原因)
+    TerminalSetupError(&'static str, io::Error),
+    TerminalError(&'static str, io::Error),
+    // (読んでいた入力, 原因)
+    InputError(&'static str, io::Error),
     UnknownWindowSize,
+    // (設定, 正しくない理由)
+    ConfigError(String, String),
+    NoFileName,
+    SaveError(PathBuf, io::Error),
+    NotFound(PathBuf),
+    PermissionDenied(PathBuf),
+    IsDirectory(PathBuf),
+    OpenError(PathBuf, io::Error),
+    ReadOnly(PathBuf),
+}
+
+impl Error {
+    // 利用者がどうすればよいかの手がかり
+    pub fn hint(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            TerminalSetupError(..) | UnknownWindowSize => {
+                Some("kirocode must be run in an interactive terminal")
+            }
+            TerminalError(..) | InputError(..) => {
+                Some("the terminal was closed or stopped responding")
+            }
+            ConfigError(..) => Some("fix or unset the setting and try again"),
+            SaveError(..) | ReadOnly(_) => {
+                Some("check the permissions of the file and its directory")
+            }
+            PermissionDenied(_) | OpenError(..) => Some("check that the file is readable"),
+            IsDirectory(_) => Some("pass the path of a file, not a directory"),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
         match self {
-            IoError(err) => write!(f, "{}", err),
-            ParseIntError(err) => write!(f, "{}", err),
+            TerminalSetupError(op, err) => write!(f, "Can't {}: {}", op, err),
+            TerminalError(op, err) => write!(f, "Can't {}: {}", op, err),
+            InputError(step, err) => write!(f, "Can't read {}: {}", step, err),
             UnknownWindowSize => write!(f, "Could not detect terminal window size"),
-            // TODO: いらいないかも
-            InputReadByteError => write!(f, "input read byte error"),
-            InputNotFoundEscapeError => write!(f, "input not found escape error"),
+            ConfigError(name, reason) => write!(f, "Invalid {}: {}", name, reason),
+            NoFileName => write!(f, "No file name to save"),
+            SaveError(path, err) => write!(f, "Can't save {}: {}", path.display(), err),
+            NotFound(path) => write!(f, "{}: No such file", path.display()),
+            PermissionDenied(path) => write!(f, "{}: Permission denied", path.display()),
+            IsDirectory(path) => write!(f, "{}: Is a directory", path.display()),
+            OpenError(path, err) => write!(f, "Can't open {}: {}", path.display(), err),
+            ReadOnly(path) => write!(f, "{} is read-only", path.display()),
         }
     }
 }
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-        Error::IoError(err)
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use Error::*;
+        match self {
+            TerminalSetupError(_, err)
+            | TerminalError(_, err)
+            | InputError(_, err)
+            | SaveError(_, err)
+            | OpenError(_, err) => Some(err),
+            _ => None,
+        }
     }
 }
 
-impl From<num::ParseIntError> for Error {
-    fn from(err: num::ParseIntError) -> Error {
-        Error::ParseIntError(err)
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source() {
+        let err = Error::SaveError(PathBuf::from("a.txt"), io::Error::other("disk full"));
+        assert_eq!(format!("{}", err), "Can't save a.txt: disk full");
+        assert_eq!(format!("{}", err.source().unwrap()), "disk full");
+        assert!(err.hint().is_some());
+
+        let err = Error::TerminalSetupError("enable raw mode", io::Error::other("not a tty"));
+        assert_eq!(format!("{}", err), "Can't enable raw mode: not a tty");
+        assert!(err.source().is_some());
+
+        assert!(Error::NoFileName.source().is_none());
+        assert!(Error::NoFileName.hint().is_none());
     }
 }
-
-pub type Result<T> = std::result::Result<T, Error>;
@@ -1,52 +1,142 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
 
+use signal_hook::consts::{SIGTSTP, SIGWINCH};
+use signal_hook::SigId;
+
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fmt;
-use std::io::Read;
+use std::io;
 use std::ops::{Deref, DerefMut};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// 貼り付けの終わりを示すシーケンス
+const PASTE_END: &[u8] = b"\x1b[201~";
+// 貼り付けの途中で入力が途切れたときに待つ時間
+const PASTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// 一度に読むバイト数
+const READ_SIZE: usize = 4096;
+
+// Esc の後にこの時間内に次のバイトが来なければ、Esc だけが押されたとみなす
+const DEFAULT_ESC_TIMEOUT: Duration = Duration::from_millis(50);
+
+// パニックしたときに戻せるように、raw モードにする前の端末の設定を覚えておく
+static ORIGINAL_MODE: Mutex<Option<(RawFd, termios::Termios)>> = Mutex::new(None);
+
+// raw モードにしていたら元の設定に戻す. パニックフックから呼ぶ
+pub fn restore_original_mode() {
+    // パニックしたのがロック中でも待たない
+    let mode = match ORIGINAL_MODE.try_lock() {
+        Ok(mut mode) => mode.take(),
+        Err(_) => return,
+    };
+    if let Some((fd, org)) = mode {
+        let _ = termios::tcsetattr(fd, termios::TCSAFLUSH, &org);
+    }
+}
 
-pub struct StdinRawMode<R: Read + AsRawFd> {
+thread_local! {
+    // カーソル位置を問い合わせて答えを待っている間は true
+    static CURSOR_QUERY: Cell<bool> = const { Cell::new(false) };
+}
+
+// ESC [1;5R はカーソル位置の答えとも Ctrl-F3 とも読めるので、
+// 問い合わせる側が答えを待っている間だけカーソル位置として読む
+pub(crate) fn expect_cursor_report(expect: bool) {
+    CURSOR_QUERY.with(|query| query.set(expect));
+}
+
+// 元の設定に戻してから SIGTSTP で自分を止める. SIGCONT で再開したら raw モードに戻す.
+// raw モードにしていなければ何もせずに false を返す
+pub fn suspend() -> Result<bool> {
+    use termios::*;
+    let mode = match ORIGINAL_MODE.lock() {
+        Ok(mode) => *mode,
+        Err(_) => None,
+    };
+    let (fd, org) = match mode {
+        Some(mode) => mode,
+        None => return Ok(false),
+    };
+
+    let raw =
+        Termios::from_fd(fd).map_err(|err| Error::TerminalError("get terminal attributes", err))?;
+    tcsetattr(fd, TCSAFLUSH, &org).map_err(|err| Error::TerminalError("disable raw mode", err))?;
+    signal_hook::low_level::raise(SIGTSTP)
+        .map_err(|err| Error::TerminalError("suspend the process", err))?;
+    // シェルの fg などで SIGCONT を受け取るとここから再開する
+    tcsetattr(fd, TCSAFLUSH, &raw).map_err(|err| Error::TerminalError("enable raw mode", err))?;
+    Ok(true)
+}
+
+pub struct StdinRawMode<R: AsRawFd> {
     stdin: R,
     org: termios::Termios,
 }
 
 impl<R> StdinRawMode<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     pub fn new(stdin: R) -> Result<Self> {
         use termios::*;
         let fd = stdin.as_raw_fd();
-        let mut termios = Termios::from_fd(fd)?;
-        let org = termios;
-
-        // C/C++ でビットの NOT 演算子は '~'
-        termios.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
-        termios.c_oflag &= !(OPOST);
-        termios.c_cflag &= !(CS8);
-        termios.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
-        termios.c_cc[VMIN] = 0;
-        termios.c_cc[VTIME] = 1;
-
-        tcsetattr(fd, TCSAFLUSH, &termios)?;
+        let org = Termios::from_fd(fd)
+            .map_err(|err| Error::TerminalSetupError("get terminal attributes", err))?;
+        tcsetattr(fd, TCSAFLUSH, &raw_mode(org))
+            .map_err(|err| Error::TerminalSetupError("enable raw mode", err))?;
+        if let Ok(mut mode) = ORIGINAL_MODE.lock() {
+            *mode = Some((fd, org));
+        }
 
         Ok(Self { stdin, org })
     }
 
-    pub fn input_keys(self) -> InputSequences<R> {
-        InputSequences { stdin: self }
+    // 端末の大きさが変わったら KeySeq::Resize を返すように SIGWINCH を受け取る
+    pub fn input_keys(self) -> Result<InputSequences<R>> {
+        let resized = Arc::new(AtomicBool::new(false));
+        let winch = signal_hook::flag::register(SIGWINCH, Arc::clone(&resized))
+            .map_err(|err| Error::TerminalSetupError("watch the window size", err))?;
+        Ok(InputSequences {
+            stdin: self,
+            pending: VecDeque::new(),
+            resized,
+            winch,
+            esc_timeout: DEFAULT_ESC_TIMEOUT,
+        })
     }
 
     fn disable_raw_mode(&mut self) {
         use termios::*;
-        tcsetattr(self.stdin.as_raw_fd(), termios::TCSAFLUSH, &self.org).unwrap();
+        // 戻せなくても終了するしかないので、エラーは無視する
+        let _ = tcsetattr(self.stdin.as_raw_fd(), termios::TCSAFLUSH, &self.org);
+        if let Ok(mut mode) = ORIGINAL_MODE.lock() {
+            *mode = None;
+        }
     }
 }
 
+fn raw_mode(org: termios::Termios) -> termios::Termios {
+    use termios::*;
+    let mut termios = org;
+    // C/C++ でビットの NOT 演算子は '~'
+    termios.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+    termios.c_oflag &= !(OPOST);
+    termios.c_cflag &= !(CS8);
+    termios.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+    termios.c_cc[VMIN] = 0;
+    termios.c_cc[VTIME] = 1;
+    termios
+}
+
 impl<R> Drop for StdinRawMode<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     fn drop(&mut self) {
         self.disable_raw_mode();
@@ -55,7 +145,7 @@ where
 
 impl<R> Deref for StdinRawMode<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     type Target = R;
 
@@ -66,13 +156,33 @@ where
 
 impl<R> DerefMut for StdinRawMode<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     fn deref_mut(&mut self) -> &mut R {
         &mut self.stdin
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    None, // ボタンを押さずに動かした
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MouseKind {
+    Press,
+    Release,
+    Drag,
+    Move,
+}
+
 #[derive(PartialEq, Debug)]
 pub enum KeySeq {
     Left,
@@ -84,8 +194,31 @@ pub enum KeySeq {
     End,
     PageUp,
     PageDown,
+    Insert,
+    BackTab,
+    // kitty のキーボードプロトコルでは Ctrl-M や Ctrl-I と区別できる
+    Enter,
+    Tab,
+    Backspace,
+    Begin, // テンキーの 5
+    F(u8),
+    Keypad(u8), // テンキーで入力した文字
+    KeypadEnter,
     Key(u8),
+    Char(char),           // ASCII 以外の UTF-8 の文字
     Cursor(usize, usize), // (x, y)
+    KeyboardFlags(usize), // kitty のキーボードプロトコルの問い合わせへの答え
+    DeviceAttributes,     // CSI c への答え
+    Paste(Vec<u8>),       // ブラケットペーストで貼り付けた内容
+    // x, y は画面の左上を 0 とした位置
+    Mouse {
+        button: MouseButton,
+        x: usize,
+        y: usize,
+        kind: MouseKind,
+    },
+    // 端末の大きさが変わった. 大きさ (w, h) が分からなければ None
+    Resize(Option<(usize, usize)>),
     Unidentified,
 }
 
@@ -102,10 +235,26 @@ impl fmt::Display for KeySeq {
             End => write!(f, "END"),
             PageUp => write!(f, "PAGE_UP"),
             PageDown => write!(f, "PAGE_DOWN"),
+            Insert => write!(f, "INSERT"),
+            BackTab => write!(f, "BACKTAB"),
+            Enter => write!(f, "ENTER"),
+            Tab => write!(f, "TAB"),
+            Backspace => write!(f, "BACKSPACE"),
+            Begin => write!(f, "BEGIN"),
+            F(n) => write!(f, "F{}", n),
+            Keypad(b) => write!(f, "KP_{}", *b as char),
+            KeypadEnter => write!(f, "KP_ENTER"),
             Key(b' ') => write!(f, "SPACE"),
             Key(b) if b.is_ascii_control() => write!(f, "\\x{:x}", b),
             Key(b) => write!(f, "{}", *b as char),
+            Char(c) => write!(f, "{}", c),
             Cursor(x, y) => write!(f, "CURSOR({},{})", x, y),
+            KeyboardFlags(flags) => write!(f, "KEYBOARD_FLAGS({})", flags),
+            DeviceAttributes => write!(f, "DEVICE_ATTRIBUTES"),
+            Paste(buf) => write!(f, "PASTE({} bytes)", buf.len()),
+            Mouse { button, x, y, kind } => write!(f, "MOUSE({:?},{:?},{},{})", button, kind, x, y),
+            Resize(Some((w, h))) => write!(f, "RESIZE({},{})", w, h),
+            Resize(None) => write!(f, "RESIZE"),
             Unidentified => write!(f, "UNKNOWN"),
         }
     }
@@ -115,6 +264,9 @@ pub struct InputSeq {
     pub key: KeySeq,
     pub ctrl: bool,
     pub alt: bool,
+    pub shift: bool,
+    pub super_key: bool,
+    pub release: bool, // キーを離した. kitty のキーボードプロトコルでだけ届く
 }
 
 impl InputSeq {
@@ -123,6 +275,9 @@ impl InputSeq {
             key,
             ctrl: false,
             alt: false,
+            shift: false,
+            super_key: false,
+            release: false,
         }
     }
 
@@ -131,6 +286,9 @@ impl InputSeq {
             key,
             ctrl: true,
             alt: false,
+            shift: false,
+            super_key: false,
+            release: false,
         }
     }
 
@@ -139,6 +297,22 @@ impl InputSeq {
             key,
             ctrl: false,
             alt: true,
+            shift: false,
+            super_key: false,
+            release: false,
+        }
+    }
+
+    // xterm の修飾キーの値は 1 + (Shift = 1, Alt = 2, Ctrl = 4, Super = 8) のビット和
+    pub fn with_modifier(key: KeySeq, modifier: usize) -> Self {
+        let bits = modifier.saturating_sub(1);
+        Self {
+            key,
+            ctrl: bits & 4 != 0,
+            alt: bits & 2 != 0,
+            shift: bits & 1 != 0,
+            super_key: bits & 8 != 0,
+            release: false,
         }
     }
 }
@@ -151,36 +325,66 @@ impl fmt::Display for InputSeq {
         if self.alt {
             write!(f, "M-")?;
         }
+        if self.shift {
+            write!(f, "S-")?;
+        }
+        if self.super_key {
+            write!(f, "s-")?;
+        }
         write!(f, "{}", self.key)
     }
 }
 
 pub struct InputSequences<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     stdin: StdinRawMode<R>,
+    // 読んだけれどまだ使っていないバイト
+    pending: VecDeque<u8>,
+    resized: Arc<AtomicBool>,
+    winch: SigId,
+    esc_timeout: Duration,
 }
 
 impl<R> InputSequences<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
+    pub fn set_esc_timeout(&mut self, timeout: Duration) {
+        self.esc_timeout = timeout;
+    }
+
     fn read_seq(&mut self) -> Result<InputSeq> {
-        if let Some(b) = self.read_byte()? {
+        if self.resized.swap(false, Ordering::Relaxed) {
+            return Ok(InputSeq::new(KeySeq::Resize(term_size::dimensions())));
+        }
+        if let Some(b) = self.read_byte("key")? {
             self.decode(b)
         } else {
             Ok(InputSeq::new(KeySeq::Unidentified))
         }
     }
 
-    fn read_byte(&mut self) -> Result<Option<u8>> {
-        let mut one_byte: [u8; 1] = [0];
-        Ok(if self.stdin.read(&mut one_byte)? == 0 {
-            None
-        } else {
-            Some(one_byte[0])
-        })
+    // step はエラーのときにどこを読んでいたかを示す
+    fn read_byte(&mut self, step: &'static str) -> Result<Option<u8>> {
+        if self.pending.is_empty() {
+            // io::Stdin は読んだ分を自分の中に溜めて、poll では続きが届いているか分からなく
+            // なるので、fd から直接読む
+            let mut buf = [0; READ_SIZE];
+            let fd = self.stdin.as_raw_fd();
+            let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                // シグナルで中断されたときは何も読めなかったことにする
+                if err.kind() == io::ErrorKind::Interrupted {
+                    return Ok(None);
+                }
+                return Err(Error::InputError(step, err));
+            }
+            self.pending.extend(&buf[..len as usize]);
+        }
+        Ok(self.pending.pop_front())
     }
 
     fn decode(&mut self, b: u8) -> Result<InputSeq> {
@@ -191,25 +395,81 @@ where
             0x1b => self.decode_escape_sequence(),
             0x00..=0x1f | 0x7f => Ok(InputSeq::ctrl(Key(b | 0b1100000))),
             0x20..=0x7e => Ok(InputSeq::new(Key(b))),
+            0xc2..=0xf4 => self.decode_utf8(b),
             _ => Ok(InputSeq::new(Unidentified)),
         }
     }
 
+    // マルチバイト文字の残りのバイトを読んで 1 文字にする
+    fn decode_utf8(&mut self, b: u8) -> Result<InputSeq> {
+        use KeySeq::*;
+
+        let len = match b {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        let mut buf = vec![b];
+        while buf.len() < len {
+            match self.read_byte("UTF-8 character")? {
+                Some(b) => buf.push(b),
+                None => return Ok(InputSeq::new(Unidentified)),
+            }
+        }
+
+        match str::from_utf8(&buf).ok().and_then(|s| s.chars().next()) {
+            Some(c) => Ok(InputSeq::new(Char(c))),
+            None => Ok(InputSeq::new(Unidentified)),
+        }
+    }
+
+    // timeout までに入力が来るか. 来なければ false
+    fn wait_input(&self, timeout: Duration) -> Result<bool> {
+        if !self.pending.is_empty() {
+            return Ok(true);
+        }
+        let mut fds = libc::pollfd {
+            fd: self.stdin.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let n = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(Error::InputError("escape sequence", err));
+        }
+        Ok(n > 0)
+    }
+
     fn decode_escape_sequence(&mut self) -> Result<InputSeq> {
         use KeySeq::*;
 
-        match self.read_byte()? {
-            Some(b'[') | Some(b'O') => {}
-            Some(b) if b.is_ascii_control() => return Ok(InputSeq::new(Key(0x1b))),
-            Some(_b) => {}
+        // 端末はエスケープシーケンスをまとめて送ってくるので、すぐに続きが来なければ Esc だけ
+        if !self.wait_input(self.esc_timeout)? {
+            return Ok(InputSeq::new(Key(0x1b)));
+        }
+        // CSI (ESC [) か SS3 (ESC O) で始まる
+        let intro = match self.read_byte("escape sequence")? {
+            Some(b @ b'[') | Some(b @ b'O') => b,
+            // ESC に続くキーは Alt を押しながら入力したもの
+            Some(b) => {
+                let mut seq = self.decode(b)?;
+                seq.alt = true;
+                return Ok(seq);
+            }
             None => return Ok(InputSeq::new(Key(0x1b))),
         };
 
+        // 引数の後の 0x40..=0x7e のバイトでシーケンスが終わる
         let mut buf = vec![];
         let cmd = loop {
-            if let Some(b) = self.read_byte()? {
+            if let Some(b) = self.read_byte("escape sequence")? {
                 match b {
-                    b'A' | b'B' | b'C' | b'D' | b'H' | b'F' | b'R' | b'~' => break b,
+                    0x40..=0x7e => break b,
                     _ => buf.push(b),
                 };
             } else {
@@ -218,41 +478,200 @@ where
             }
         };
 
+        if intro == b'[' && buf.first() == Some(&b'<') && (cmd == b'M' || cmd == b'm') {
+            return Ok(decode_mouse(&buf[1..], cmd));
+        }
+
+        if intro == b'[' && buf.first() == Some(&b'?') {
+            return Ok(match cmd {
+                b'u' => match parse_bytes_as_usize(&buf[1..]) {
+                    Some(flags) => InputSeq::new(KeyboardFlags(flags)),
+                    None => InputSeq::new(Unidentified),
+                },
+                b'c' => InputSeq::new(DeviceAttributes),
+                _ => InputSeq::new(Unidentified),
+            });
+        }
+        if intro == b'[' && cmd == b'u' {
+            return Ok(decode_kitty_key(&buf));
+        }
+
         let mut args = buf.split(|b| *b == b';');
-        match cmd {
-            b'R' => {
-                let mut i = args.filter_map(parse_bytes_as_usize);
-                match (i.next(), i.next()) {
-                    (Some(x), Some(y)) => Ok(InputSeq::new(Cursor(x, y))),
-                    _ => Ok(InputSeq::new(Unidentified)),
-                }
-            }
+        if intro == b'[' && cmd == b'R' && CURSOR_QUERY.with(Cell::get) {
+            let mut i = args.filter_map(parse_bytes_as_usize);
+            return match (i.next(), i.next()) {
+                (Some(x), Some(y)) => Ok(InputSeq::new(Cursor(x, y))),
+                _ => Ok(InputSeq::new(Unidentified)),
+            };
+        }
+
+        // xterm は修飾キーを 2 番目の引数で送ってくる. 例: ESC [1;5C は Ctrl-Right
+        let first = args.next().and_then(parse_bytes_as_usize);
+        let modifier = args.next().and_then(parse_bytes_as_usize).unwrap_or(1);
+
+        if intro == b'[' && cmd == b'~' && first == Some(200) {
+            return self.read_paste();
+        }
 
-            b'~' => match args.next() {
-                Some(b"1") | Some(b"7") => return Ok(InputSeq::new(Home)),
-                Some(b"4") | Some(b"8") => return Ok(InputSeq::new(End)),
-                Some(b"3") => return Ok(InputSeq::new(Del)),
-                Some(b"5") => return Ok(InputSeq::new(PageUp)),
-                Some(b"6") => return Ok(InputSeq::new(PageDown)),
+        let key = match (intro, cmd) {
+            (_, b'~') => match first {
+                Some(1) | Some(7) => Home,
+                Some(2) => Insert,
+                Some(3) => Del,
+                Some(4) | Some(8) => End,
+                Some(5) => PageUp,
+                Some(6) => PageDown,
+                // F5 の前と F10 の後は番号が 1 つ飛んでいる
+                Some(n @ 11..=15) => F((n - 10) as u8),
+                Some(n @ 17..=21) => F((n - 11) as u8),
+                Some(n @ 23..=24) => F((n - 12) as u8),
                 _ => return Ok(InputSeq::new(Unidentified)),
             },
 
-            b'A' => return Ok(InputSeq::new(Up)),
-            b'B' => return Ok(InputSeq::new(Down)),
-            b'C' => return Ok(InputSeq::new(Right)),
-            b'D' => return Ok(InputSeq::new(Left)),
-
-            b'H' | b'F' => {
-                let key = match cmd {
-                    b'H' => Home,
-                    b'F' => End,
-                    _ => unreachable!(),
-                };
-                return Ok(InputSeq::new(key));
+            (_, b'A') => Up,
+            (_, b'B') => Down,
+            (_, b'C') => Right,
+            (_, b'D') => Left,
+            (_, b'H') => Home,
+            (_, b'F') => End,
+            (_, b'E') => Begin,
+            // 問い合わせていないときに届いたカーソル位置の答えは捨てる
+            (b'[', b'R') if first.is_some_and(|n| n != 1) => {
+                return Ok(InputSeq::new(Unidentified))
             }
+            (_, b'P'..=b'S') => F(cmd - b'P' + 1),
+            (b'[', b'Z') => BackTab,
+
+            // アプリケーションキーパッドモードのテンキー
+            (b'O', b'M') => KeypadEnter,
+            (b'O', b'X') => Keypad(b'='),
+            (b'O', b'j'..=b'y') => Keypad(cmd - 0x40),
+
+            _ => return Ok(InputSeq::new(Unidentified)),
+        };
+        Ok(InputSeq::with_modifier(key, modifier))
+    }
 
-            _ => unreachable!(),
+    // ESC [200~ の後から ESC [201~ までをそのまま読む
+    fn read_paste(&mut self) -> Result<InputSeq> {
+        let mut buf = vec![];
+        let mut stalled = false;
+        while !buf.ends_with(PASTE_END) {
+            match self.read_byte("bracketed paste")? {
+                Some(b) => {
+                    buf.push(b);
+                    stalled = false;
+                }
+                // 届くのが遅れているだけかもしれないので少し待つ.
+                // それでも終わりが来なければ、そこまでを貼り付ける
+                None => {
+                    if stalled || !self.wait_input(PASTE_TIMEOUT)? {
+                        return Ok(InputSeq::new(KeySeq::Paste(buf)));
+                    }
+                    stalled = true;
+                }
+            }
         }
+        buf.truncate(buf.len() - PASTE_END.len());
+        Ok(InputSeq::new(KeySeq::Paste(buf)))
+    }
+}
+
+impl<R> Drop for InputSequences<R>
+where
+    R: AsRawFd,
+{
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.winch);
+    }
+}
+
+// kitty のキーボードプロトコルの CSI code;modifiers:event u を読む.
+// code の後の ':' 以降 (Shift を押したときの文字など) と 3 番目の引数 (入力した文字) は使わない
+fn decode_kitty_key(buf: &[u8]) -> InputSeq {
+    use KeySeq::*;
+
+    // 引数はさらに ':' で区切られている
+    let mut args = buf.split(|b| *b == b';').map(|arg| {
+        arg.split(|b| *b == b':')
+            .map(parse_bytes_as_usize)
+            .collect::<Vec<_>>()
+    });
+    let code = match args.next().as_deref() {
+        Some([Some(code), ..]) => *code,
+        _ => return InputSeq::new(Unidentified),
+    };
+    let modifiers = args.next().unwrap_or_default();
+    let modifier = modifiers.first().copied().flatten().unwrap_or(1);
+    let event = modifiers.get(1).copied().flatten().unwrap_or(1);
+
+    let key = match code {
+        9 => Tab,
+        13 => Enter,
+        27 => Key(0x1b),
+        127 => Backspace,
+        0x20..=0x7e => Key(code as u8),
+        // テンキーなどは私用領域の番号で届く
+        57376..=57398 => F((code - 57376 + 13) as u8),
+        57399..=57408 => Keypad(b'0' + (code - 57399) as u8),
+        57409 => Keypad(b'.'),
+        57410 => Keypad(b'/'),
+        57411 => Keypad(b'*'),
+        57412 => Keypad(b'-'),
+        57413 => Keypad(b'+'),
+        57414 => KeypadEnter,
+        57415 => Keypad(b'='),
+        0xe000..=0xf8ff => Unidentified,
+        _ => match std::char::from_u32(code as u32) {
+            Some(c) => Char(c),
+            None => Unidentified,
+        },
+    };
+    let mut seq = InputSeq::with_modifier(key, modifier);
+    // 1 は押した, 2 は押し続けている, 3 は離した
+    seq.release = event == 3;
+    seq
+}
+
+// SGR 形式のマウスの報告 ESC [<b;x;yM を読む. 離したときは最後が m になる
+fn decode_mouse(buf: &[u8], cmd: u8) -> InputSeq {
+    use KeySeq::*;
+
+    let mut args = buf.split(|b| *b == b';').map(parse_bytes_as_usize);
+    let (b, x, y) = match (args.next(), args.next(), args.next()) {
+        (Some(Some(b)), Some(Some(x)), Some(Some(y))) if x > 0 && y > 0 => (b, x, y),
+        _ => return InputSeq::new(Unidentified),
+    };
+
+    // 下位 2 ビットがボタン, 4/8/16 が修飾キー, 32 が移動, 64 がホイール
+    let button = match (b & 64 != 0, b & 3) {
+        (false, 0) => MouseButton::Left,
+        (false, 1) => MouseButton::Middle,
+        (false, 2) => MouseButton::Right,
+        (false, _) => MouseButton::None,
+        (true, 0) => MouseButton::WheelUp,
+        (true, 1) => MouseButton::WheelDown,
+        (true, 2) => MouseButton::WheelLeft,
+        (true, _) => MouseButton::WheelRight,
+    };
+    let kind = match (cmd, b & 32 != 0) {
+        (b'm', _) => MouseKind::Release,
+        (_, true) if button == MouseButton::None => MouseKind::Move,
+        (_, true) => MouseKind::Drag,
+        _ => MouseKind::Press,
+    };
+    InputSeq {
+        key: Mouse {
+            button,
+            x: x - 1,
+            y: y - 1,
+            kind,
+        },
+        ctrl: b & 16 != 0,
+        alt: b & 8 != 0,
+        shift: b & 4 != 0,
+        super_key: false,
+        release: false,
     }
 }
 
@@ -262,7 +681,7 @@ fn parse_bytes_as_usize(b: &[u8]) -> Option<usize> {
 
 impl<R> Iterator for InputSequences<R>
 where
-    R: Read + AsRawFd,
+    R: AsRawFd,
 {
     type Item = Result<InputSeq>;
 
@@ -288,45 +707,70 @@ impl Iterator for DummyInputSequences {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
     use std::io::Write;
-    use std::os::unix::io::RawFd;
+    use std::os::unix::io::{FromRawFd, RawFd};
 
     use KeySeq::*;
 
-    struct DummyStdin(Vec<u8>);
+    // テスト用の端末. master に書き込んだものが slave から読める
+    struct Pty {
+        master: File,
+        slave: File,
+    }
 
-    impl Read for DummyStdin {
-        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            buf.as_mut().write(&self.0).unwrap();
-            if self.0.len() > 0 {
-                self.0.remove(0);
-            }
-            Ok(buf.len())
+    fn pty() -> Pty {
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0);
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+            let master = File::from_raw_fd(fd);
+            let name = CStr::from_ptr(libc::ptsname(fd))
+                .to_str()
+                .unwrap()
+                .to_string();
+            let slave = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(name)
+                .unwrap();
+            Pty { master, slave }
         }
     }
 
-    impl AsRawFd for DummyStdin {
+    // slave から読む
+    impl AsRawFd for Pty {
         fn as_raw_fd(&self) -> RawFd {
-            0
+            self.slave.as_raw_fd()
         }
     }
 
-    fn dummy_input_keys(buf: &[u8]) -> InputSequences<DummyStdin> {
+    // 端末から buf を一度に送る
+    fn dummy_input_keys(buf: &[u8]) -> InputSequences<Pty> {
         use termios::*;
-        let stdin = DummyStdin(buf.to_vec());
-        let fd = stdin.as_raw_fd();
-        let org = Termios::from_fd(fd).unwrap();
+        let mut stdin = pty();
+        let org = Termios::from_fd(stdin.as_raw_fd()).unwrap();
+        tcsetattr(stdin.as_raw_fd(), TCSANOW, &raw_mode(org)).unwrap();
+        stdin.master.write_all(buf).unwrap();
         let stdin = StdinRawMode { stdin, org };
-        stdin.input_keys()
+        stdin.input_keys().unwrap()
+    }
+
+    #[test]
+    fn test_suspend() {
+        // raw モードにしていなければ止まらない
+        assert!(!suspend().unwrap());
     }
 
     #[test]
     fn test_read_byte() {
         let mut i = dummy_input_keys(b"");
-        assert_eq!(i.read_byte().unwrap().unwrap(), 0);
+        assert_eq!(i.read_byte("key").unwrap(), None);
 
         i = dummy_input_keys(b"a");
-        assert_eq!(i.read_byte().unwrap().unwrap(), b'a');
+        assert_eq!(i.read_byte("key").unwrap().unwrap(), b'a');
     }
 
     #[test]
@@ -340,23 +784,48 @@ mod tests {
         // ctrl
         let seq = i.decode(0x00).unwrap();
         assert_eq!(seq.key, Key(0x60));
-        assert_eq!(seq.ctrl, true);
-        assert_eq!(seq.alt, false);
+        assert!(seq.ctrl);
+        assert!(!seq.alt);
 
         let seq = i.decode(0x1f).unwrap();
         assert_eq!(seq.key, Key(127));
-        assert_eq!(seq.ctrl, true);
-        assert_eq!(seq.alt, false);
+        assert!(seq.ctrl);
+        assert!(!seq.alt);
 
         let seq = i.decode(0x7f).unwrap();
         assert_eq!(seq.key, Key(127));
-        assert_eq!(seq.ctrl, true);
-        assert_eq!(seq.alt, false);
+        assert!(seq.ctrl);
+        assert!(!seq.alt);
 
         let seq = i.decode(0x20).unwrap();
         assert_eq!(seq.key, Key(32));
-        assert_eq!(seq.ctrl, false);
-        assert_eq!(seq.alt, false);
+        assert!(!seq.ctrl);
+        assert!(!seq.alt);
+    }
+
+    #[test]
+    fn test_decode_utf8() {
+        let mut i = dummy_input_keys("é".as_bytes());
+        let b = i.read_byte("key").unwrap().unwrap();
+        assert_eq!(i.decode(b).unwrap().key, Char('é'));
+
+        let mut i = dummy_input_keys("あ".as_bytes());
+        let b = i.read_byte("key").unwrap().unwrap();
+        assert_eq!(i.decode(b).unwrap().key, Char('あ'));
+
+        let mut i = dummy_input_keys("🦀".as_bytes());
+        let b = i.read_byte("key").unwrap().unwrap();
+        assert_eq!(i.decode(b).unwrap().key, Char('🦀'));
+
+        // 続きのバイトが不正
+        let mut i = dummy_input_keys(b"\xe3a");
+        let b = i.read_byte("key").unwrap().unwrap();
+        assert_eq!(i.decode(b).unwrap().key, Unidentified);
+
+        // 先頭になれないバイト
+        let mut i = dummy_input_keys(b"");
+        assert_eq!(i.decode(0xc0).unwrap().key, Unidentified);
+        assert_eq!(i.decode(0xff).unwrap().key, Unidentified);
     }
 
     #[test]
@@ -366,11 +835,73 @@ mod tests {
         assert_eq!(seq.key, Key(0x1b));
     }
 
+    #[test]
+    fn test_decode_alt() {
+        let mut i = dummy_input_keys(b"x");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(b'x'));
+        assert!(seq.alt && !seq.ctrl);
+
+        // Alt と Ctrl を一緒に押した
+        let mut i = dummy_input_keys(b"\x01");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(b'a'));
+        assert!(seq.alt && seq.ctrl);
+
+        let mut i = dummy_input_keys("é".as_bytes());
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Char('é'));
+        assert!(seq.alt);
+
+        // Esc を 2 回押した
+        let mut i = dummy_input_keys(b"\x1b");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(0x1b));
+        assert!(seq.alt);
+    }
+
+    #[test]
+    fn test_read_whole_sequence() {
+        // 端末はシーケンスを一度に送ってくる. Esc を読んだ後に続きを待っても、もう届いている
+        let mut i = dummy_input_keys(b"\x1b[B\x1b[200~ab\x1b[201~\x1b[<0;3;4M\x13");
+        assert_eq!(i.next().unwrap().unwrap().key, Down);
+        assert_eq!(i.next().unwrap().unwrap().key, Paste(b"ab".to_vec()));
+        assert!(matches!(
+            i.next().unwrap().unwrap().key,
+            Mouse { x: 2, y: 3, .. }
+        ));
+        let seq = i.next().unwrap().unwrap();
+        assert_eq!(seq.key, Key(b's'));
+        assert!(seq.ctrl);
+    }
+
+    #[test]
+    fn test_esc_timeout() {
+        // 続きがまだ届いていなければ Esc だけ
+        let mut i = dummy_input_keys(b"");
+        i.set_esc_timeout(Duration::from_millis(1));
+        assert_eq!(i.decode(0x1b).unwrap().key, Key(0x1b));
+    }
+
     #[test]
     fn test_decode_escape_sequence_cursor() {
+        expect_cursor_report(true);
         let mut i = dummy_input_keys(b"[24;80R");
         let seq = i.decode(0x1b).unwrap();
         assert_eq!(seq.key, Cursor(24, 80));
+        let mut i = dummy_input_keys(b"[1;5R");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Cursor(1, 5));
+
+        // 問い合わせていなければ修飾キー付きの F3
+        expect_cursor_report(false);
+        let mut i = dummy_input_keys(b"[1;5R");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, F(3));
+        assert!(seq.ctrl);
+        let mut i = dummy_input_keys(b"[24;80R");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Unidentified);
     }
 
     #[test]
@@ -432,22 +963,194 @@ mod tests {
         assert_eq!(seq.key, End);
     }
 
+    #[test]
+    fn test_decode_escape_sequence_modifier() {
+        let mut i = dummy_input_keys(b"[1;5C");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Right);
+        assert!(seq.ctrl && !seq.shift && !seq.alt && !seq.super_key);
+
+        let mut i = dummy_input_keys(b"[1;2A");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Up);
+        assert!(seq.shift && !seq.ctrl);
+
+        let mut i = dummy_input_keys(b"[1;4H");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Home);
+        assert!(seq.shift && seq.alt && !seq.ctrl);
+
+        let mut i = dummy_input_keys(b"[3;9~");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Del);
+        assert!(seq.super_key && !seq.shift);
+
+        let mut i = dummy_input_keys(b"[6;6~");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, PageDown);
+        assert!(seq.ctrl && seq.shift);
+        assert_eq!(format!("{}", seq), "C-S-PAGE_DOWN");
+    }
+
+    #[test]
+    fn test_decode_escape_sequence_function_keys() {
+        let cases: Vec<(&[u8], KeySeq)> = vec![
+            (b"OP", F(1)),
+            (b"OS", F(4)),
+            (b"[11~", F(1)),
+            (b"[15~", F(5)),
+            (b"[17~", F(6)),
+            (b"[21~", F(10)),
+            (b"[23~", F(11)),
+            (b"[24~", F(12)),
+            (b"[2~", Insert),
+            (b"[Z", BackTab),
+            (b"[E", Begin),
+            (b"OM", KeypadEnter),
+            (b"Op", Keypad(b'0')),
+            (b"Oy", Keypad(b'9')),
+            (b"Oj", Keypad(b'*')),
+            (b"Om", Keypad(b'-')),
+            (b"OX", Keypad(b'=')),
+            (b"[16~", Unidentified),
+            (b"Oz", Unidentified),
+        ];
+        for (buf, key) in cases {
+            let mut i = dummy_input_keys(buf);
+            assert_eq!(i.decode(0x1b).unwrap().key, key);
+        }
+
+        // 修飾キー付き
+        let mut i = dummy_input_keys(b"[1;5P");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, F(1));
+        assert!(seq.ctrl);
+        let mut i = dummy_input_keys(b"[15;2~");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, F(5));
+        assert!(seq.shift);
+        assert_eq!(format!("{}", seq), "S-F5");
+        assert_eq!(format!("{}", Keypad(b'+')), "KP_+");
+    }
+
+    #[test]
+    fn test_decode_paste() {
+        let mut i = dummy_input_keys(b"[200~a\x1b[Ab\r\x1b[201~x");
+        assert_eq!(i.decode(0x1b).unwrap().key, Paste(b"a\x1b[Ab\r".to_vec()));
+        assert_eq!(i.next().unwrap().unwrap().key, Key(b'x'));
+
+        // 終わりが来なければそこまで
+        let mut i = dummy_input_keys(b"[200~abc");
+        assert_eq!(i.decode(0x1b).unwrap().key, Paste(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_mouse() {
+        let mouse = |button, x, y, kind| Mouse { button, x, y, kind };
+
+        let mut i = dummy_input_keys(b"[<0;10;5M");
+        assert_eq!(
+            i.decode(0x1b).unwrap().key,
+            mouse(MouseButton::Left, 9, 4, MouseKind::Press)
+        );
+        let mut i = dummy_input_keys(b"[<0;10;5m");
+        assert_eq!(
+            i.decode(0x1b).unwrap().key,
+            mouse(MouseButton::Left, 9, 4, MouseKind::Release)
+        );
+        let mut i = dummy_input_keys(b"[<32;11;5M");
+        assert_eq!(
+            i.decode(0x1b).unwrap().key,
+            mouse(MouseButton::Left, 10, 4, MouseKind::Drag)
+        );
+        let mut i = dummy_input_keys(b"[<35;1;1M");
+        assert_eq!(
+            i.decode(0x1b).unwrap().key,
+            mouse(MouseButton::None, 0, 0, MouseKind::Move)
+        );
+        let mut i = dummy_input_keys(b"[<65;1;2M");
+        assert_eq!(
+            i.decode(0x1b).unwrap().key,
+            mouse(MouseButton::WheelDown, 0, 1, MouseKind::Press)
+        );
+
+        // Ctrl を押しながら右クリック
+        let mut i = dummy_input_keys(b"[<18;3;4M");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, mouse(MouseButton::Right, 2, 3, MouseKind::Press));
+        assert!(seq.ctrl && !seq.shift && !seq.alt);
+
+        let mut i = dummy_input_keys(b"[<0;0;5M");
+        assert_eq!(i.decode(0x1b).unwrap().key, Unidentified);
+    }
+
+    #[test]
+    fn test_decode_kitty_key() {
+        // Ctrl-I と Tab を区別できる
+        let mut i = dummy_input_keys(b"[105;5u");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(b'i'));
+        assert!(seq.ctrl && !seq.release);
+        let mut i = dummy_input_keys(b"[9u");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Tab);
+        assert!(!seq.ctrl);
+
+        let mut i = dummy_input_keys(b"[13;2u");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Enter);
+        assert!(seq.shift);
+
+        // 離したとき
+        let mut i = dummy_input_keys(b"[97;1:3u");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(b'a'));
+        assert!(seq.release);
+
+        // Shift を押したときの文字と入力した文字は使わない
+        let mut i = dummy_input_keys(b"[97:65;10;65u");
+        let seq = i.decode(0x1b).unwrap();
+        assert_eq!(seq.key, Key(b'a'));
+        assert!(seq.shift && seq.super_key && !seq.ctrl);
+
+        let cases: Vec<(&[u8], KeySeq)> = vec![
+            (b"[127u", Backspace),
+            (b"[27u", Key(0x1b)),
+            (b"[12354u", Char('あ')),
+            (b"[57399u", Keypad(b'0')),
+            (b"[57414u", KeypadEnter),
+            (b"[57376u", F(13)),
+            (b"[57358u", Unidentified),
+            (b"[u", Unidentified),
+        ];
+        for (buf, key) in cases {
+            let mut i = dummy_input_keys(buf);
+            assert_eq!(i.decode(0x1b).unwrap().key, key);
+        }
+
+        // 問い合わせへの答え
+        let mut i = dummy_input_keys(b"[?1u");
+        assert_eq!(i.decode(0x1b).unwrap().key, KeyboardFlags(1));
+        let mut i = dummy_input_keys(b"[?62;22c");
+        assert_eq!(i.decode(0x1b).unwrap().key, DeviceAttributes);
+    }
+
     #[test]
     fn test_input_seq() {
         let i1 = InputSeq::new(Key(b'a'));
         assert_eq!(i1.key, Key(b'a'));
-        assert_eq!(i1.ctrl, false);
-        assert_eq!(i1.alt, false);
+        assert!(!i1.ctrl);
+        assert!(!i1.alt);
 
         let i2 = InputSeq::ctrl(Key(b'a'));
         assert_eq!(i2.key, Key(b'a'));
-        assert_eq!(i2.ctrl, true);
-        assert_eq!(i2.alt, false);
+        assert!(i2.ctrl);
+        assert!(!i2.alt);
 
         let i3 = InputSeq::alt(Key(b'a'));
         assert_eq!(i3.key, Key(b'a'));
-        assert_eq!(i3.ctrl, false);
-        assert_eq!(i3.alt, true);
+        assert!(!i3.ctrl);
+        assert!(i3.alt);
     }
 
     #[test]
@@ -459,4 +1162,12 @@ mod tests {
         assert_eq!(parse_bytes_as_usize(b"1"), Some(1));
         assert_eq!(parse_bytes_as_usize(b"10"), Some(10));
     }
+
+    #[test]
+    fn test_resize() {
+        let mut i = dummy_input_keys(b"a");
+        i.resized.store(true, Ordering::Relaxed);
+        assert!(matches!(i.next().unwrap().unwrap().key, Resize(_)));
+        assert_eq!(i.next().unwrap().unwrap().key, Key(b'a'));
+    }
 }
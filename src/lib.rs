@@ -1,9 +1,19 @@
+mod buffer;
 mod editor;
 mod error;
 mod input;
+mod row;
 mod screen;
+mod syntax;
+mod undo;
 
-pub use editor::{Editor, EditorRow};
+pub use buffer::{Buffer, Lines};
+pub use editor::{Editor, LineEnding};
 pub use error::{Error, Result};
-pub use input::{DummyInputSequences, InputSeq, KeySeq, StdinRawMode};
-pub use screen::Screen;
+pub use input::{
+    restore_original_mode, DummyInputSequences, InputSeq, KeySeq, MouseButton, MouseKind,
+    StdinRawMode,
+};
+pub use row::EditorRow;
+pub use screen::{reset_terminal, Screen, Status};
+pub use syntax::{Highlight, HlState, Syntax};
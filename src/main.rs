@@ -1,9 +1,26 @@
-use kirocode::{Editor, Error, Result, StdinRawMode};
+use kirocode::{reset_terminal, restore_original_mode, Editor, Error, Result, StdinRawMode};
 
+use std::backtrace::Backtrace;
 use std::env;
 use std::io::{self, BufWriter};
+use std::panic;
+use std::process;
+use std::time::Duration;
+
+// タブ幅を変えるときに使う環境変数
+const TAB_STOP_VAR: &str = "KIROCODE_TAB_STOP";
+// Esc の後に続きを待つミリ秒数
+const ESC_TIMEOUT_VAR: &str = "KIROCODE_ESC_TIMEOUT";
 
 fn main() {
+    // パニックのメッセージが代替画面に消えたり崩れたりしないように、先に端末を戻す
+    panic::set_hook(Box::new(|info| {
+        restore_original_mode();
+        reset_terminal();
+        eprintln!("kirocode {}", info);
+        eprintln!("{}", Backtrace::force_capture());
+    }));
+
     let args: Vec<String> = env::args().collect();
     let mut filepath: Option<&str> = None;
     if args.len() >= 2 {
@@ -15,17 +32,58 @@ fn main() {
 }
 
 fn edit(filepath: Option<&str>) -> Result<()> {
-    let input = StdinRawMode::new(io::stdin())?.input_keys();
+    let tab_stop = config(TAB_STOP_VAR, 1)?;
+    let esc_timeout = config(ESC_TIMEOUT_VAR, 0)?;
+    let mut input = StdinRawMode::new(io::stdin())?.input_keys()?;
+    if let Some(ms) = esc_timeout {
+        input.set_esc_timeout(Duration::from_millis(ms as u64));
+    }
     let output = io::stdout();
     let output = BufWriter::new(output.lock());
-    match filepath {
-        Some(f) => Editor::open(f, input, output)?.edit(),
-        _ => Editor::new(input, output)?.edit(),
+    let mut editor = match filepath {
+        Some(f) => Editor::open(f, input, output)?,
+        _ => Editor::new(input, output)?,
+    };
+    if let Some(tab_stop) = tab_stop {
+        editor.set_tab_stop(tab_stop);
+    }
+    editor.detect_kitty_keyboard()?;
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| editor.edit()));
+    match result {
+        Ok(result) => result,
+        Err(payload) => {
+            // パニックしても保存していない変更は残す
+            match editor.write_recovery() {
+                Ok(Some(path)) => eprintln!("Unsaved changes were written to {}", path.display()),
+                Ok(None) => {}
+                Err(err) => eprintln!("Could not write unsaved changes: {}", err),
+            }
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+// 環境変数の設定を読む. min 以上の数でなければエラー
+fn config(name: &str, min: usize) -> Result<Option<usize>> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    match value.parse() {
+        Ok(n) if n >= min => Ok(Some(n)),
+        _ => Err(Error::ConfigError(
+            format!("{}={:?}", name, value),
+            format!("expected a number of at least {}", min),
+        )),
     }
 }
 
 fn die(err: Error) {
-    print!("\x1b[2J");
-    print!("\x1b[H");
-    eprintln!("{}", err);
+    reset_terminal();
+    eprintln!("kirocode: {}", err);
+    if let Some(hint) = err.hint() {
+        eprintln!("hint: {}", hint);
+    }
+    process::exit(1);
 }
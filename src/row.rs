@@ -0,0 +1,244 @@
+use crate::syntax::{Highlight, HlState, Syntax};
+
+use std::str;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub const DEFAULT_TAB_STOP: usize = 8;
+
+// 不正な UTF-8 のバイトは置換文字で表示する
+const REPLACEMENT: &str = "\u{fffd}";
+
+// 書記素クラスタ 1 つ分の位置
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cell {
+    pub cx: usize, // buf 上の開始位置
+    pub rx: usize, // 画面上の開始桁
+    pub ri: usize, // render 上の開始位置
+}
+
+// バッファの 1 行を画面に表示する形にしたもの
+pub struct EditorRow {
+    pub size: usize,
+    pub buf: Vec<u8>,
+    // 画面に表示する形式. タブは空白に展開し、制御文字は ^X で表す
+    pub render: Vec<u8>,
+    // render の各バイトのハイライト
+    pub hl: Vec<Highlight>,
+    // 書記素クラスタごとの位置. 末尾に行末を表す番兵を持つ
+    cells: Vec<Cell>,
+}
+
+impl EditorRow {
+    pub fn new(buf: Vec<u8>, tab_stop: usize) -> Self {
+        let mut row = Self {
+            size: buf.len(),
+            buf,
+            render: vec![],
+            hl: vec![],
+            cells: vec![],
+        };
+        row.update_render(tab_stop);
+        row
+    }
+
+    // 画面上の幅
+    pub fn width(&self) -> usize {
+        self.cells.last().map_or(0, |cell| cell.rx)
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    fn update_render(&mut self, tab_stop: usize) {
+        self.render.clear();
+        self.cells.clear();
+
+        let mut cx = 0;
+        let mut rx = 0;
+        for chunk in self.buf.utf8_chunks() {
+            for g in chunk.valid().graphemes(true) {
+                self.cells.push(Cell {
+                    cx,
+                    rx,
+                    ri: self.render.len(),
+                });
+                rx += render_grapheme(&mut self.render, g, rx, tab_stop);
+                cx += g.len();
+            }
+            for _ in chunk.invalid() {
+                self.cells.push(Cell {
+                    cx,
+                    rx,
+                    ri: self.render.len(),
+                });
+                self.render.extend(REPLACEMENT.as_bytes());
+                rx += 1;
+                cx += 1;
+            }
+        }
+        self.cells.push(Cell {
+            cx,
+            rx,
+            ri: self.render.len(),
+        });
+    }
+
+    // cx が書記素クラスタの途中を指しているときはその先頭の桁を返す
+    pub fn cx_to_rx(&self, cx: usize) -> usize {
+        let i = self.cells.partition_point(|cell| cell.cx <= cx);
+        self.cells[i - 1].rx
+    }
+
+    pub fn cx_to_ri(&self, cx: usize) -> usize {
+        let i = self.cells.partition_point(|cell| cell.cx <= cx);
+        self.cells[i - 1].ri
+    }
+
+    // rx が文字の途中を指しているときはその文字の位置を返す
+    pub fn rx_to_cx(&self, rx: usize) -> usize {
+        let i = self.cells.partition_point(|cell| cell.rx <= rx);
+        self.cells[i - 1].cx
+    }
+
+    // 次の書記素クラスタの位置
+    pub fn next_cx(&self, cx: usize) -> usize {
+        match self.cells.iter().find(|cell| cell.cx > cx) {
+            Some(cell) => cell.cx,
+            None => self.size,
+        }
+    }
+
+    // 前の書記素クラスタの位置
+    pub fn prev_cx(&self, cx: usize) -> usize {
+        match self.cells.iter().rev().find(|cell| cell.cx < cx) {
+            Some(cell) => cell.cx,
+            None => 0,
+        }
+    }
+
+    // start の状態から行をハイライトし、行末の状態を返す
+    pub(crate) fn update_highlight(&mut self, syntax: Option<&Syntax>, start: HlState) -> HlState {
+        let (hl, state) = match syntax {
+            Some(syntax) => syntax.highlight(&self.render, start),
+            None => (vec![Highlight::Normal; self.render.len()], HlState::Normal),
+        };
+        self.hl = hl;
+        state
+    }
+}
+
+// 書記素クラスタを render に書き出し、画面上の幅を返す
+fn render_grapheme(render: &mut Vec<u8>, g: &str, rx: usize, tab_stop: usize) -> usize {
+    if g == "\t" {
+        let width = tab_stop - rx % tab_stop;
+        render.extend(" ".repeat(width).as_bytes());
+        return width;
+    }
+
+    if g.bytes().all(|b| b.is_ascii_control()) {
+        for b in g.bytes() {
+            render.push(b'^');
+            render.push(b ^ 0x40);
+        }
+        return g.len() * 2;
+    }
+
+    // C1 制御文字などそのまま出力すると端末が崩れるもの
+    if g.chars().any(char::is_control) {
+        render.extend(REPLACEMENT.as_bytes());
+        return 1;
+    }
+
+    render.extend(g.as_bytes());
+    g.width()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_editor_row_render() {
+        let row = EditorRow::new(b"a\tb".to_vec(), 4);
+        assert_eq!(row.render, b"a   b");
+        assert_eq!(row.width(), 5);
+
+        let row = EditorRow::new(b"\t\t".to_vec(), 8);
+        assert_eq!(row.width(), 16);
+
+        // 制御文字は ^X で表示
+        let row = EditorRow::new(b"\x01\x7f".to_vec(), 8);
+        assert_eq!(row.render, b"^A^?");
+        assert_eq!(row.width(), 4);
+    }
+
+    #[test]
+    fn test_editor_row_render_utf8() {
+        let row = EditorRow::new("あいう".as_bytes().to_vec(), 8);
+        assert_eq!(row.render, "あいう".as_bytes());
+        assert_eq!(row.size, 9);
+        assert_eq!(row.width(), 6);
+
+        // 結合文字は幅 0
+        let row = EditorRow::new("e\u{301}x".as_bytes().to_vec(), 8);
+        assert_eq!(row.width(), 2);
+        assert_eq!(row.cells().len(), 3);
+
+        // 不正な UTF-8 は置換文字
+        let row = EditorRow::new(b"a\xffb".to_vec(), 8);
+        assert_eq!(row.render, "a\u{fffd}b".as_bytes());
+        assert_eq!(row.width(), 3);
+        assert_eq!(row.next_cx(1), 2);
+    }
+
+    #[test]
+    fn test_cx_to_rx() {
+        let row = EditorRow::new(b"a\tb\x02c".to_vec(), 4);
+        assert_eq!(row.cx_to_rx(0), 0);
+        assert_eq!(row.cx_to_rx(1), 1);
+        assert_eq!(row.cx_to_rx(2), 4);
+        assert_eq!(row.cx_to_rx(3), 5);
+        assert_eq!(row.cx_to_rx(4), 7);
+        assert_eq!(row.cx_to_rx(5), 8);
+        assert_eq!(row.cx_to_rx(100), 8);
+
+        assert_eq!(row.rx_to_cx(0), 0);
+        assert_eq!(row.rx_to_cx(2), 1);
+        assert_eq!(row.rx_to_cx(4), 2);
+        assert_eq!(row.rx_to_cx(6), 3);
+        assert_eq!(row.rx_to_cx(8), 5);
+        assert_eq!(row.rx_to_cx(100), 5);
+    }
+
+    #[test]
+    fn test_cx_to_rx_wide() {
+        // "aあb": あ は 3 バイトで幅 2
+        let row = EditorRow::new("aあb".as_bytes().to_vec(), 8);
+        assert_eq!(row.cx_to_rx(1), 1);
+        assert_eq!(row.cx_to_rx(2), 1);
+        assert_eq!(row.cx_to_rx(4), 3);
+        assert_eq!(row.cx_to_rx(5), 4);
+
+        assert_eq!(row.rx_to_cx(1), 1);
+        assert_eq!(row.rx_to_cx(2), 1);
+        assert_eq!(row.rx_to_cx(3), 4);
+    }
+
+    #[test]
+    fn test_next_prev_cx() {
+        // が (か + 濁点) は 1 つの書記素クラスタ
+        let row = EditorRow::new("aか\u{3099}b".as_bytes().to_vec(), 8);
+        assert_eq!(row.next_cx(0), 1);
+        assert_eq!(row.next_cx(1), 7);
+        assert_eq!(row.next_cx(7), 8);
+        assert_eq!(row.next_cx(8), 8);
+
+        assert_eq!(row.prev_cx(8), 7);
+        assert_eq!(row.prev_cx(7), 1);
+        assert_eq!(row.prev_cx(1), 0);
+        assert_eq!(row.prev_cx(0), 0);
+    }
+}
@@ -1,20 +1,59 @@
-use crate::editor::EditorRow;
+use crate::buffer::Buffer;
 use crate::error::{Error, Result};
-use crate::input::{InputSeq, KeySeq};
+use crate::input::{self, InputSeq, KeySeq};
+use crate::row::{EditorRow, DEFAULT_TAB_STOP};
+use crate::syntax::Highlight;
 
-use std::io::Write;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const VERSION: &str = "0.0.1";
 
+// ステータスバーとメッセージバーの行数
+const BAR_ROWS: usize = 2;
+
+// メッセージを表示し続ける時間
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// setup で切り替えたモードを戻して、カーソルを表示し、代替画面から元の画面に戻る
+const RESET_MODES: &[u8] = b"\x1b[?1006l\x1b[?1002l\x1b[?2004l\x1b[?25h\x1b[?1049l";
+
+// setup で切り替えたモードを戻すシーケンス. エラーやパニックで終わるときに reset_terminal で使う
+static ENABLED_MODES: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+// 端末への問い合わせの答えを待つ回数. 入力は 100ms ごとに途切れる
+const QUERY_WAITS: usize = 10;
+
+// ステータスバーに表示する編集中のファイルの情報
+pub struct Status<'a> {
+    pub filename: Option<&'a str>,
+    pub filetype: Option<&'a str>,
+    pub dirty: bool,
+    // 行の位置を調べている間の進み具合 (%)
+    pub indexing: Option<usize>,
+    pub new_file: bool,
+    pub read_only: bool,
+}
+
 pub struct Screen<W: Write> {
     cx: usize,
     cy: usize,
+    rx: usize, // render 上のカーソル位置
     rows: usize,
     cols: usize,
     row_off: usize,
     col_off: usize,
     output: W,
     buf: Vec<u8>,
+    frame: Vec<Vec<u8>>, // 描画中の画面. 1 行ずつ
+    shown: Vec<Vec<u8>>, // 端末に表示されている画面. 空なら全体を描き直す
+    message: Option<(String, Instant)>,
+    tab_stop: usize,
+    kitty_keyboard: bool, // kitty のキーボードプロトコルを使う
 }
 
 impl<W> Screen<W>
@@ -25,33 +64,58 @@ where
     where
         I: Iterator<Item = Result<InputSeq>>,
     {
-        let buf = Vec::new();
-        if let Some((w, h)) = size {
-            return Ok(Self {
-                cx: 0,
-                cy: 0,
-                rows: h,
-                cols: w,
-                row_off: 0,
-                col_off: 0,
-                output,
-                buf,
-            });
-        }
-
-        let (w, h) = get_window_size(input, &mut output)?;
+        let (w, h) = match size {
+            Some(size) => size,
+            None => get_window_size(input, &mut output)?,
+        };
+
+        // 下の 2 行はステータスバーとメッセージバーに使う
+        let rows = if h > BAR_ROWS { h - BAR_ROWS } else { 1 };
+
         Ok(Self {
             cx: 0,
             cy: 0,
-            rows: h,
+            rx: 0,
+            rows,
             cols: w,
             row_off: 0,
             col_off: 0,
             output,
-            buf,
+            buf: Vec::new(),
+            frame: Vec::new(),
+            shown: Vec::new(),
+            message: None,
+            tab_stop: DEFAULT_TAB_STOP,
+            kitty_keyboard: false,
         })
     }
 
+    // 端末の大きさが変わったときに呼ぶ. size が分からなければ端末に問い合わせる
+    pub fn resize<I>(&mut self, size: Option<(usize, usize)>, input: I) -> Result<()>
+    where
+        I: Iterator<Item = Result<InputSeq>>,
+    {
+        let (w, h) = match size {
+            Some(size) => size,
+            None => get_window_size(input, &mut self.output)?,
+        };
+        self.rows = if h > BAR_ROWS { h - BAR_ROWS } else { 1 };
+        self.cols = w;
+
+        // はみ出したオフセットを戻す. カーソルが見えるようにするのは次の scroll で行う
+        self.row_off = self.row_off.min(self.cy);
+        self.col_off = self.col_off.min(self.rx);
+
+        // 前の大きさで描いた内容が残らないように、次の描画で画面全体を消す
+        self.redraw_all();
+        Ok(())
+    }
+
+    // 表示されている内容が分からなくなったので、次の描画で画面全体を消して描き直す
+    pub fn redraw_all(&mut self) {
+        self.shown.clear();
+    }
+
     // getter
 
     pub fn rows(&self) -> usize {
@@ -62,40 +126,196 @@ where
         self.cols
     }
 
+    pub fn cx(&self) -> usize {
+        self.cx
+    }
+
+    pub fn cy(&self) -> usize {
+        self.cy
+    }
+
+    pub fn row_off(&self) -> usize {
+        self.row_off
+    }
+
+    pub fn col_off(&self) -> usize {
+        self.col_off
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|(message, _)| message.as_str())
+    }
+
     // setter
 
     pub fn set_cx(&mut self, cx: usize) {
         self.cx = cx;
     }
 
+    pub fn set_cy(&mut self, cy: usize) {
+        self.cy = cy;
+    }
+
+    pub fn set_row_off(&mut self, row_off: usize) {
+        self.row_off = row_off;
+    }
+
+    pub fn set_col_off(&mut self, col_off: usize) {
+        self.col_off = col_off;
+    }
+
+    // 端末に書いた内容をテストで調べる
+    #[cfg(test)]
+    pub(crate) fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn tab_stop(&self) -> usize {
+        self.tab_stop
+    }
+
+    pub fn set_tab_stop(&mut self, tab_stop: usize) {
+        self.tab_stop = if tab_stop > 0 { tab_stop } else { 1 };
+    }
+
+    pub fn set_message<S: Into<String>>(&mut self, message: S) {
+        self.message = Some((message.into(), Instant::now()));
+    }
+
+    // 編集を始めるときに端末のモードを切り替える. シェルの画面を壊さないように代替画面に描き、
+    // 貼り付けは ESC [200~ と ESC [201~ で囲んでもらい、マウスはドラッグも含めて SGR 形式で報告してもらう
+    pub fn setup(&mut self) -> Result<()> {
+        let mut modes = b"\x1b[?1049h\x1b[?2004h\x1b[?1002h\x1b[?1006h".to_vec();
+        if self.kitty_keyboard {
+            // 紛らわしいキーを区別するだけのモードにする
+            modes.extend_from_slice(b"\x1b[>1u");
+        }
+        if let Ok(mut enabled) = ENABLED_MODES.lock() {
+            *enabled = Some(self.reset_modes());
+        }
+        self.output
+            .write_all(&modes)
+            .and_then(|_| self.output.flush())
+            .map_err(|err| Error::TerminalError("set up the terminal", err))
+    }
+
+    // kitty のキーボードプロトコルに対応しているか問い合わせる. 対応していれば setup で有効にする.
+    // 対応していない端末も CSI c には答えるので、その答えが先に来たら対応していない.
+    // 答えを待つ間に押されたキーは捨てずに typed に入れる
+    pub fn detect_kitty_keyboard<I>(&mut self, input: I, typed: &mut Vec<InputSeq>) -> Result<bool>
+    where
+        I: Iterator<Item = Result<InputSeq>>,
+    {
+        self.output
+            .write_all(b"\x1b[?u\x1b[c")
+            .and_then(|_| self.output.flush())
+            .map_err(|err| Error::TerminalError("query the keyboard protocol", err))?;
+
+        // 答えない端末でも待ち続けないように、入力のない時間が続いたらやめる
+        let mut waits = 0;
+        for seq in input {
+            let seq = seq?;
+            match seq.key {
+                KeySeq::KeyboardFlags(_) => self.kitty_keyboard = true,
+                KeySeq::DeviceAttributes => break,
+                KeySeq::Unidentified if waits < QUERY_WAITS => waits += 1,
+                KeySeq::Unidentified => break,
+                _ => typed.push(seq),
+            }
+        }
+        Ok(self.kitty_keyboard)
+    }
+
+    // setup で切り替えたモードを元に戻す
+    pub fn restore(&mut self) -> Result<()> {
+        let modes = self.reset_modes();
+        self.output
+            .write_all(&modes)
+            .and_then(|_| self.output.flush())
+            .map_err(|err| Error::TerminalError("restore the terminal", err))?;
+        if let Ok(mut enabled) = ENABLED_MODES.lock() {
+            *enabled = None;
+        }
+        Ok(())
+    }
+
+    fn reset_modes(&self) -> Vec<u8> {
+        let mut modes = vec![];
+        if self.kitty_keyboard {
+            modes.extend_from_slice(b"\x1b[<u");
+        }
+        modes.extend_from_slice(RESET_MODES);
+        modes
+    }
+
     pub fn clear(&mut self) -> Result<()> {
-        self.output.write(b"\x1b[2J")?;
-        self.output.write(b"\x1b[H")?;
+        self.shown.clear();
+        self.output
+            .write_all(b"\x1b[2J\x1b[H")
+            .map_err(|err| Error::TerminalError("clear the screen", err))?;
         Ok(())
     }
 
-    pub fn refresh(&mut self, num_size: usize, rows: &mut Vec<EditorRow>) -> Result<()> {
-        self.scroll();
+    // row は画面に表示する行を返す. ハイライトはここで付けてもらう
+    pub fn refresh<F>(&mut self, buffer: &Buffer, row: F, status: &Status) -> Result<()>
+    where
+        F: Fn(usize) -> EditorRow,
+    {
+        self.scroll(buffer);
 
-        self.append_buffers(b"\x1b[?25l");
-        self.append_buffers(b"\x1b[H");
+        self.frame.clear();
+        self.draw_rows(buffer, &row);
+        self.draw_status_bar(buffer.line_count(), status);
+        self.draw_message_bar();
 
-        self.draw_rows(num_size, rows);
+        self.append_buffers(b"\x1b[?25l");
+        self.draw_frame();
 
         // cursor
-        let buf = format!("\x1b[{};{}H", (self.cy - self.row_off) + 1, self.cx + 1);
+        let buf = format!(
+            "\x1b[{};{}H",
+            (self.cy - self.row_off) + 1,
+            (self.rx - self.col_off) + 1
+        );
         self.append_buffers(buf.as_bytes());
 
         self.append_buffers(b"\x1b[?25h");
 
-        self.output.write(&self.buf)?;
-        self.output.flush()?; // 描画後は flush しないとカーソルの位置が上に戻らない
+        // 描画後は flush しないとカーソルの位置が上に戻らない
+        self.output
+            .write_all(&self.buf)
+            .and_then(|_| self.output.flush())
+            .map_err(|err| Error::TerminalError("draw the screen", err))?;
         self.buf = vec![];
 
         Ok(())
     }
 
-    fn draw_rows(&mut self, num_rows: usize, rows: &mut Vec<EditorRow>) {
+    // 前に表示した画面と比べて、変わった行だけを書き直す
+    fn draw_frame(&mut self) {
+        let frame = mem::take(&mut self.frame);
+        if self.shown.is_empty() {
+            self.append_buffers(b"\x1b[2J");
+        }
+        for (y, line) in frame.iter().enumerate() {
+            let start = match self.shown.get(y) {
+                Some(shown) if shown == line => continue,
+                Some(shown) => same_prefix_len(shown, line),
+                None => 0,
+            };
+            // 行末まで消してから書く. 画面の幅ちょうどの行の後で消すと最後の桁まで消える端末がある
+            let buf = format!("\x1b[{};{}H\x1b[K", y + 1, start + 1);
+            self.append_buffers(buf.as_bytes());
+            self.append_buffers(&line[start..]);
+        }
+        self.shown = frame;
+    }
+
+    fn draw_rows<F>(&mut self, buffer: &Buffer, row: &F)
+    where
+        F: Fn(usize) -> EditorRow,
+    {
+        let num_rows = buffer.line_count();
         for y in 0..self.rows {
             let file_row = y + self.row_off;
             if file_row >= num_rows {
@@ -127,39 +347,132 @@ where
                     self.append_buffers(b"~");
                 }
             } else {
-                if let Some(row) = rows.get(file_row) {
-                    let len = match row.size.checked_sub(self.col_off) {
-                        Some(_) => {
-                            let l = row.size - self.col_off;
-                            if l > self.cols {
-                                self.cols
-                            } else {
-                                l
-                            }
-                        }
-                        None => 0,
-                    };
-                    if self.col_off > len {
-                        // TODO: 右側にスクロールすると左側に消えたはずのテキストが再度表示される
-                        self.append_buffers(&row.buf);
-                    } else {
-                        self.append_buffers(&row.buf[self.col_off..len]);
-                    }
+                self.draw_row(&row(file_row));
+            }
+            self.end_line();
+        }
+    }
+
+    // col_off から画面の幅に収まる分だけ描画する
+    fn draw_row(&mut self, row: &EditorRow) {
+        let end = self.col_off + self.cols;
+        let mut color = Highlight::Normal;
+        for cell in row.cells().windows(2) {
+            let (cell, next) = (cell[0], cell[1]);
+            if cell.rx < self.col_off {
+                // 左端で途切れた全角文字は空白で埋める
+                if next.rx > self.col_off {
+                    let width = next.rx.min(end) - self.col_off;
+                    self.append_buffers(" ".repeat(width).as_bytes());
                 }
+                continue;
+            }
+            if cell.rx >= end {
+                break;
             }
+            if next.rx > end {
+                // 右端で途切れる全角文字も空白で埋める
+                self.append_buffers(" ".repeat(end - cell.rx).as_bytes());
+                break;
+            }
+            let hl = row.hl.get(cell.ri).copied().unwrap_or(Highlight::Normal);
+            if hl != color {
+                // 反転表示は文字色では戻らないので先に戻す
+                if color == Highlight::Selection {
+                    self.append_buffers(b"\x1b[27m");
+                }
+                self.append_buffers(format!("\x1b[{}m", hl.color()).as_bytes());
+                if hl == Highlight::Selection {
+                    self.append_buffers(b"\x1b[7m");
+                }
+                color = hl;
+            }
+            self.append_buffers(&row.render[cell.ri..next.ri]);
+        }
+        match color {
+            Highlight::Normal => {}
+            // 選択中の文字色は元に戻してあるので、反転表示だけを戻す
+            Highlight::Selection => self.append_buffers(b"\x1b[27m"),
+            _ => self.append_buffers(b"\x1b[39m"),
+        }
+    }
+
+    fn draw_status_bar(&mut self, num_rows: usize, status: &Status) {
+        let filename = truncate_width(status.filename.unwrap_or("[No Name]"), 20);
+        let modified = if status.dirty { " (modified)" } else { "" };
+        let indexing = match status.indexing {
+            Some(percent) => format!(" (indexing {}%)", percent),
+            None => String::new(),
+        };
+        let flags = match (status.new_file, status.read_only) {
+            (true, _) => " [new file]",
+            (_, true) => " [read-only]",
+            _ => "",
+        };
+        let left = format!(
+            "{}{} - {} lines{}{}",
+            filename, flags, num_rows, indexing, modified
+        );
+        let right = format!(
+            "{} | {}:{}",
+            status.filetype.unwrap_or("no ft"),
+            self.cy + 1,
+            self.rx + 1
+        );
+
+        let left = truncate_width(&left, self.cols);
+        let mut len = left.width();
+
+        self.append_buffers(b"\x1b[7m");
+        self.append_buffers(left.as_bytes());
+        // 右寄せで表示できる余白があるときだけ右側を表示する
+        let right_len = right.width();
+        while len < self.cols {
+            if self.cols - len == right_len {
+                self.append_buffers(right.as_bytes());
+                break;
+            }
+            self.append_buffers(b" ");
+            len += 1;
+        }
+        self.append_buffers(b"\x1b[m");
+        self.end_line();
+    }
 
-            self.append_buffers(b"\x1b[K");
-            if y < self.rows - 1 {
-                self.append_buffers(b"\r\n");
+    fn draw_message_bar(&mut self) {
+        if let Some((message, time)) = &self.message {
+            if time.elapsed() < MESSAGE_TIMEOUT {
+                let message = truncate_width(message, self.cols);
+                self.buf.extend(message.as_bytes());
             }
         }
+        self.end_line();
+    }
+
+    // ここまでに描いた内容を 1 行として画面に加える
+    fn end_line(&mut self) {
+        let line = mem::take(&mut self.buf);
+        self.frame.push(line);
     }
 
     fn append_buffers(&mut self, buf: &[u8]) {
         self.buf.extend(buf);
     }
 
-    fn scroll(&mut self) {
+    // カーソルの移動に使う行. ハイライトは付けない
+    fn row(&self, buffer: &Buffer, y: usize) -> Option<EditorRow> {
+        if y < buffer.line_count() {
+            Some(EditorRow::new(buffer.line(y), self.tab_stop))
+        } else {
+            None
+        }
+    }
+
+    pub fn scroll(&mut self, buffer: &Buffer) {
+        self.rx = self
+            .row(buffer, self.cy)
+            .map_or(0, |row| row.cx_to_rx(self.cx));
+
         // カーソルが可視ウィンドウ上にあるなら、カーソル位置まで移動
         if self.cy < self.row_off {
             self.row_off = self.cy;
@@ -168,36 +481,126 @@ where
         if self.cy >= self.row_off + self.rows {
             self.row_off = self.cy - self.rows + 1;
         }
-        if self.cx < self.col_off {
-            self.col_off = self.cx;
+        if self.rx < self.col_off {
+            self.col_off = self.rx;
         }
-        if self.cx >= self.col_off + self.cols {
-            self.col_off = self.cx - self.cols + 1;
+        if self.rx >= self.col_off + self.cols {
+            self.col_off = self.rx - self.cols + 1;
         }
     }
 
-    pub fn move_cursor(&mut self, key: KeySeq, buf_rows: usize) {
+    // 画面上の位置 (x, y) にあるバッファの位置 (cx, cy). ステータスバーなどの上なら None
+    pub fn cursor_at(&self, x: usize, y: usize, buffer: &Buffer) -> Option<(usize, usize)> {
+        if y >= self.rows {
+            return None;
+        }
+        let cy = (self.row_off + y).min(buffer.line_count());
+        let cx = self
+            .row(buffer, cy)
+            .map_or(0, |row| row.rx_to_cx(self.col_off + x));
+        Some((cx, cy))
+    }
+
+    // ホイールで表示する範囲を n 行ずらす. カーソルは画面の中に残す
+    pub fn scroll_lines(&mut self, up: bool, n: usize, buffer: &Buffer) {
+        let last = buffer.line_count().saturating_sub(1);
+        self.row_off = if up {
+            self.row_off.saturating_sub(n)
+        } else {
+            (self.row_off + n).min(last.max(self.row_off))
+        };
+
+        let cy = self
+            .cy
+            .max(self.row_off)
+            .min(self.row_off + self.rows - 1)
+            .min(buffer.line_count());
+        if cy != self.cy {
+            let rx = self
+                .row(buffer, self.cy)
+                .map_or(0, |row| row.cx_to_rx(self.cx));
+            self.cy = cy;
+            self.cx = self.row(buffer, cy).map_or(0, |row| row.rx_to_cx(rx));
+        }
+    }
+
+    pub fn move_cursor(&mut self, key: KeySeq, buffer: &Buffer) {
         use KeySeq::*;
+        let row = self.row(buffer, self.cy);
         match key {
             Left => {
                 if self.cx > 0 {
-                    self.cx -= 1;
-                }
-            }
-            Right => self.cx += 1,
-            Up => {
-                if self.cy > 0 {
+                    self.cx = row.map_or(0, |row| row.prev_cx(self.cx));
+                } else if self.cy > 0 {
+                    // 行頭なら前の行の行末へ
                     self.cy -= 1;
+                    self.cx = self.row(buffer, self.cy).map_or(0, |row| row.size);
                 }
             }
-            Down => {
-                if self.cy < buf_rows {
-                    self.cy += 1;
+            Right => {
+                if let Some(row) = row {
+                    if self.cx < row.size {
+                        self.cx = row.next_cx(self.cx);
+                    } else {
+                        // 行末なら次の行の行頭へ
+                        self.cy += 1;
+                        self.cx = 0;
+                    }
                 }
             }
+            Up if self.cy > 0 => {
+                let rx = row.map_or(0, |row| row.cx_to_rx(self.cx));
+                self.cy -= 1;
+                self.cx = self.row(buffer, self.cy).map_or(0, |row| row.rx_to_cx(rx));
+            }
+            Down if self.cy < buffer.line_count() => {
+                let rx = row.map_or(0, |row| row.cx_to_rx(self.cx));
+                self.cy += 1;
+                self.cx = self.row(buffer, self.cy).map_or(0, |row| row.rx_to_cx(rx));
+            }
             _ => {}
         }
+
+        // 行を移動したときにカーソルが行末より右にあれば行末に合わせる
+        let size = self.row(buffer, self.cy).map_or(0, |row| row.size);
+        if self.cx > size {
+            self.cx = size;
+        }
+    }
+}
+
+// エラーやパニックで終わるときに、setup で切り替えたモードがあれば元に戻す
+pub fn reset_terminal() {
+    // パニックしたのがロック中でも待たない
+    let modes = match ENABLED_MODES.try_lock() {
+        Ok(mut modes) => modes.take(),
+        Err(_) => return,
+    };
+    if let Some(modes) = modes {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&modes).and_then(|_| stdout.flush());
+    }
+}
+
+// 画面上の幅が width に収まるように切り詰める
+fn truncate_width(s: &str, width: usize) -> &str {
+    let mut w = 0;
+    for (i, c) in s.char_indices() {
+        w += c.width().unwrap_or(0);
+        if w > width {
+            return &s[..i];
+        }
     }
+    s
+}
+
+// 前の行と同じで書き直さなくてよいバイト数. 桁の位置がバイト数と同じになるように、
+// エスケープシーケンスや ASCII 以外の文字の手前までしか比べない
+fn same_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b)
+        .take_while(|(a, b)| a == b && (0x20..0x7f).contains(*a))
+        .count()
 }
 
 fn get_window_size<I, W>(input: I, output: W) -> Result<(usize, usize)>
@@ -217,17 +620,24 @@ where
     I: Iterator<Item = Result<InputSeq>>,
     W: Write,
 {
-    // カーソルを画面右下に移動してフォールバックとしてサイズを取得する
-    output.write(b"\x1b[999C\x1b[999B\x1b[6n")?;
-    output.flush()?;
+    input::expect_cursor_report(true);
+    let result = (|| {
+        // カーソルを画面右下に移動してフォールバックとしてサイズを取得する
+        output
+            .write_all(b"\x1b[999C\x1b[999B\x1b[6n")
+            .and_then(|_| output.flush())
+            .map_err(|err| Error::TerminalError("query the cursor position", err))?;
 
-    for seq in input {
-        if let KeySeq::Cursor(w, h) = seq?.key {
-            return Ok((w, h));
+        for seq in input {
+            if let KeySeq::Cursor(w, h) = seq?.key {
+                return Ok((w, h));
+            }
         }
-    }
 
-    Err(Error::UnknownWindowSize)
+        Err(Error::UnknownWindowSize)
+    })();
+    input::expect_cursor_report(false);
+    result
 }
 
 #[cfg(test)]
@@ -236,31 +646,24 @@ mod tests {
 
     use crate::error::Error;
     use crate::input::DummyInputSequences;
+    use crate::row::DEFAULT_TAB_STOP;
 
     use KeySeq::*;
 
     #[cfg(test)]
     use pretty_assertions::assert_eq;
 
-    fn editor_rows_to_buf(erows: Vec<EditorRow>, rows: usize) -> Vec<u8> {
-        let mut buf = vec![];
-        for i in 0..erows.len() {
-            let e = &erows[i];
-            buf.extend(e.buf[..e.size].iter());
-            buf.extend(b"\x1b[K");
-            if i < rows - 1 {
-                buf.extend(b"\r\n");
-            }
+    fn editor_rows_to_frame(erows: Vec<EditorRow>, rows: usize) -> Vec<Vec<u8>> {
+        let mut frame = vec![];
+        for e in erows.iter() {
+            frame.push(e.render.clone());
         }
 
-        for i in erows.len()..rows {
-            buf.extend(b"~\x1b[K");
-            if i < rows - 1 {
-                buf.extend(b"\r\n");
-            }
+        for _ in erows.len()..rows {
+            frame.push(b"~".to_vec());
         }
 
-        return buf;
+        frame
     }
 
     #[test]
@@ -283,7 +686,8 @@ mod tests {
         match Screen::new(Some((50, 100)), input, output) {
             Ok(screen) => {
                 assert_eq!(screen.cols, 50);
-                assert_eq!(screen.rows, 100);
+                // 下の 2 行はステータスバーとメッセージバー
+                assert_eq!(screen.rows, 98);
             }
             _ => unreachable!(),
         };
@@ -337,7 +741,7 @@ mod tests {
 
         s.cy = 100;
         s.row_off = 101;
-        s.scroll();
+        s.scroll(&Buffer::new());
         assert_eq!(s.row_off, 100);
 
         // scroll
@@ -346,8 +750,26 @@ mod tests {
         let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
         s.cy = 150;
         s.row_off = 50;
-        s.scroll();
-        assert_eq!(s.row_off, 51);
+        s.scroll(&Buffer::new());
+        assert_eq!(s.row_off, 53);
+    }
+
+    fn buffer(lines: &[&[u8]]) -> Buffer {
+        let mut buf = vec![];
+        for line in lines {
+            buf.extend_from_slice(line);
+            buf.push(b'\n');
+        }
+        Buffer::from(buf)
+    }
+
+    fn dummy_rows(n: usize, line: &[u8]) -> Buffer {
+        buffer(&vec![line; n])
+    }
+
+    // ハイライトを付けずに表示する
+    fn plain_rows(buffer: &Buffer, tab_stop: usize) -> impl Fn(usize) -> EditorRow + '_ {
+        move |y| EditorRow::new(buffer.line(y), tab_stop)
     }
 
     #[test]
@@ -356,27 +778,36 @@ mod tests {
         let input = DummyInputSequences(vec![]);
         let output: Vec<u8> = vec![];
         let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
-        screen.move_cursor(Left, 1);
+        let rows = dummy_rows(1, b"hello");
+        screen.move_cursor(Left, &rows);
         assert_eq!(screen.cx, 0);
-        screen.move_cursor(Right, 1);
+        screen.move_cursor(Right, &rows);
         assert_eq!(screen.cx, 1);
 
-        // right
+        // right: 行末で止まらず次の行の行頭へ
         let input = DummyInputSequences(vec![]);
         let output: Vec<u8> = vec![];
         let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
-        for _ in 0..100 {
-            screen.move_cursor(Right, 1);
+        let rows = dummy_rows(2, b"hello");
+        for _ in 0..5 {
+            screen.move_cursor(Right, &rows);
         }
-        assert_eq!(screen.cx, 50);
+        assert_eq!((screen.cx, screen.cy), (5, 0));
+        screen.move_cursor(Right, &rows);
+        assert_eq!((screen.cx, screen.cy), (0, 1));
+
+        // left: 行頭なら前の行の行末へ
+        screen.move_cursor(Left, &rows);
+        assert_eq!((screen.cx, screen.cy), (5, 0));
 
         // up
         let input = DummyInputSequences(vec![]);
         let output: Vec<u8> = vec![];
         let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
-        screen.move_cursor(Up, 10);
-        assert_eq!(screen.cx, 0);
-        screen.move_cursor(Down, 10);
+        let rows = dummy_rows(10, b"");
+        screen.move_cursor(Up, &rows);
+        assert_eq!(screen.cy, 0);
+        screen.move_cursor(Down, &rows);
         assert_eq!(screen.cy, 1);
 
         // down
@@ -384,13 +815,23 @@ mod tests {
         let output: Vec<u8> = vec![];
         let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
         for _ in 0..200 {
-            screen.move_cursor(Down, 10);
+            screen.move_cursor(Down, &rows);
         }
         assert_eq!(screen.cy, 10);
+        let rows = dummy_rows(100, b"");
         for _ in 0..200 {
-            screen.move_cursor(Down, 100);
+            screen.move_cursor(Down, &rows);
         }
         assert_eq!(screen.cy, 100);
+
+        // 短い行に移動したら行末に合わせる
+        let input = DummyInputSequences(vec![]);
+        let output: Vec<u8> = vec![];
+        let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
+        let rows = buffer(&[b"hello", b"hi"]);
+        screen.set_cx(5);
+        screen.move_cursor(Down, &rows);
+        assert_eq!((screen.cx, screen.cy), (2, 1));
     }
 
     #[test]
@@ -398,27 +839,15 @@ mod tests {
         let i = DummyInputSequences(vec![]);
         let o: Vec<u8> = vec![];
         let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
-        s.draw_rows(0, &mut vec![]);
+        let b = Buffer::new();
+        s.draw_rows(&b, &plain_rows(&b, DEFAULT_TAB_STOP));
 
-        let mut buf: Vec<u8> = vec![];
-        for _ in 0..33 {
-            buf.extend(b"~\x1b[K\r\n");
-        }
-        buf.extend(b"~");
+        // ステータスバーとメッセージバーの分、描画する行は 98 行になる
+        let mut frame = vec![b"~".to_vec(); 98];
         // NOTE: 12かと思ったけどなぜか10. 理由がわかっていない
-        for _ in 0..10 {
-            buf.extend(b" ");
-        }
-        buf.extend(b"KiroCode -- version 0.0.1\x1b[K\r\n");
-        for _ in 0..65 {
-            buf.extend(b"~\x1b[K\r\n");
-        }
-        buf.extend(b"~\x1b[K");
+        frame[32] = format!("~{}KiroCode -- version 0.0.1", " ".repeat(10)).into_bytes();
 
-        assert_eq!(
-            String::from_utf8(s.buf).unwrap(),
-            String::from_utf8(buf).unwrap(),
-        );
+        assert_eq!(s.frame, frame);
     }
 
     #[test]
@@ -427,25 +856,14 @@ mod tests {
         let o: Vec<u8> = vec![];
         let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
 
-        let mut erows = vec![
-            EditorRow {
-                buf: b"hello".to_vec(),
-                size: 5,
-            },
-            EditorRow {
-                buf: b"world".to_vec(),
-                size: 5,
-            },
-            EditorRow {
-                buf: b"kirocode".to_vec(),
-                size: 8,
-            },
+        let erows = vec![
+            EditorRow::new(b"hello".to_vec(), DEFAULT_TAB_STOP),
+            EditorRow::new(b"world".to_vec(), DEFAULT_TAB_STOP),
+            EditorRow::new(b"kirocode".to_vec(), DEFAULT_TAB_STOP),
         ];
-        s.draw_rows(3, &mut erows);
-        assert_eq!(
-            String::from_utf8(s.buf),
-            String::from_utf8(editor_rows_to_buf(erows, 100)),
-        );
+        let b = buffer(&[b"hello", b"world", b"kirocode"]);
+        s.draw_rows(&b, &plain_rows(&b, DEFAULT_TAB_STOP));
+        assert_eq!(s.frame, editor_rows_to_frame(erows, 98));
     }
 
     #[test]
@@ -454,15 +872,33 @@ mod tests {
         let o: Vec<u8> = vec![];
         let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
 
-        let mut erows = vec![EditorRow {
-            buf: b"hello".to_vec(),
-            size: 5,
-        }];
+        let erows = vec![EditorRow::new(b"hello".to_vec(), DEFAULT_TAB_STOP)];
+        let b = buffer(&[b"hello"]);
 
-        s.refresh(1, &mut erows).unwrap();
+        let status = Status {
+            filename: None,
+            filetype: None,
+            dirty: false,
+            indexing: None,
+            new_file: false,
+            read_only: false,
+        };
+        s.refresh(&b, plain_rows(&b, DEFAULT_TAB_STOP), &status)
+            .unwrap();
+
+        let mut frame = editor_rows_to_frame(erows, 98);
+        let mut status_bar = b"\x1b[7m[No Name] - 1 lines".to_vec();
+        status_bar.extend(" ".repeat(50 - 19 - 11).as_bytes());
+        status_bar.extend(b"no ft | 1:1\x1b[m");
+        frame.push(status_bar);
+        frame.push(vec![]);
 
-        let mut buf = b"\x1b[?25l\x1b[H".to_vec();
-        buf.extend(editor_rows_to_buf(erows, 100));
+        // 最初は画面全体を描く
+        let mut buf = b"\x1b[?25l\x1b[2J".to_vec();
+        for (y, line) in frame.iter().enumerate() {
+            buf.extend(format!("\x1b[{};1H\x1b[K", y + 1).as_bytes());
+            buf.extend(line);
+        }
         buf.extend(b"\x1b[1;1H\x1b[?25h");
 
         // output
@@ -470,5 +906,391 @@ mod tests {
 
         // refresh
         assert_eq!(s.buf, vec![]);
+        assert_eq!(s.shown, frame);
+
+        // 変わっていなければカーソルだけ動かす
+        s.output = vec![];
+        s.refresh(&b, plain_rows(&b, DEFAULT_TAB_STOP), &status)
+            .unwrap();
+        assert_eq!(s.output, b"\x1b[?25l\x1b[1;1H\x1b[?25h".to_vec());
+
+        // 変わった行の変わったところから後ろだけを書き直す
+        let b = buffer(&[b"help"]);
+        s.output = vec![];
+        s.refresh(&b, plain_rows(&b, DEFAULT_TAB_STOP), &status)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(s.output).unwrap(),
+            "\x1b[?25l\x1b[1;4H\x1b[Kp\x1b[1;1H\x1b[?25h"
+        );
+
+        // 描き直しを頼まれたら画面全体を描く
+        s.output = vec![];
+        s.redraw_all();
+        s.refresh(&b, plain_rows(&b, DEFAULT_TAB_STOP), &status)
+            .unwrap();
+        assert!(s
+            .output
+            .starts_with(b"\x1b[?25l\x1b[2J\x1b[1;1H\x1b[Khelp\x1b[2;1H"));
+    }
+
+    #[test]
+    fn test_same_prefix_len() {
+        assert_eq!(same_prefix_len(b"hello", b"help"), 3);
+        assert_eq!(same_prefix_len(b"abc", b"abcdef"), 3);
+        // エスケープシーケンスや ASCII 以外の文字の後ろは比べない
+        assert_eq!(same_prefix_len(b"a\x1b[33mb", b"a\x1b[33mc"), 1);
+        assert_eq!(same_prefix_len("aあい".as_bytes(), "aあう".as_bytes()), 1);
+    }
+
+    #[test]
+    fn test_draw_status_bar() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
+        s.rx = 3;
+        s.cy = 9;
+        let status = Status {
+            filename: Some("main.rs"),
+            filetype: Some("rs"),
+            dirty: true,
+            indexing: None,
+            new_file: false,
+            read_only: false,
+        };
+        s.draw_status_bar(20, &status);
+
+        let left = "main.rs - 20 lines (modified)";
+        let right = "rs | 10:4";
+        let mut buf = b"\x1b[7m".to_vec();
+        buf.extend(left.as_bytes());
+        buf.extend(" ".repeat(50 - left.len() - right.len()).as_bytes());
+        buf.extend(right.as_bytes());
+        buf.extend(b"\x1b[m");
+        assert_eq!(String::from_utf8(s.frame.remove(0)), String::from_utf8(buf));
+
+        // 幅が足りなければ右側は表示しない
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 100)), i, o).unwrap();
+        let status = Status {
+            filename: Some("a_very_long_file_name.txt"),
+            filetype: None,
+            dirty: false,
+            indexing: None,
+            new_file: false,
+            read_only: false,
+        };
+        s.draw_status_bar(1, &status);
+        assert_eq!(s.frame, vec![b"\x1b[7ma_very_lon\x1b[m".to_vec()]);
+
+        // 行の位置を調べている間は進み具合を表示する
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((40, 100)), i, o).unwrap();
+        let status = Status {
+            filename: Some("big.log"),
+            filetype: None,
+            dirty: false,
+            indexing: Some(42),
+            new_file: false,
+            read_only: false,
+        };
+        s.draw_status_bar(1000, &status);
+        let left = "big.log - 1000 lines (indexing 42%)";
+        assert!(s.frame[0].starts_with(format!("\x1b[7m{}", left).as_bytes()));
+    }
+
+    #[test]
+    fn test_mouse_position() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 7)), i, o).unwrap();
+        let b = buffer(&[b"\tab", b"cd", b"", b"ef", b"gh", b"ij", b"kl", b"mn"]);
+
+        assert_eq!(s.cursor_at(9, 0, &b), Some((2, 0)));
+        // タブの途中はタブの位置
+        assert_eq!(s.cursor_at(3, 0, &b), Some((0, 0)));
+        assert_eq!(s.cursor_at(0, 1, &b), Some((0, 1)));
+        assert_eq!(s.cursor_at(5, 1, &b), Some((2, 1)));
+        // ステータスバーの上
+        assert_eq!(s.cursor_at(0, 5, &b), None);
+
+        s.row_off = 4;
+        s.col_off = 1;
+        assert_eq!(s.cursor_at(0, 1, &b), Some((1, 5)));
+        assert_eq!(s.cursor_at(0, 4, &b), Some((0, 8)));
+    }
+
+    #[test]
+    fn test_scroll_lines() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 5)), i, o).unwrap();
+        let b = dummy_rows(10, b"hello");
+        s.cx = 4;
+
+        // カーソルは画面の一番上の行に残る
+        s.scroll_lines(false, 3, &b);
+        assert_eq!((s.row_off, s.cx, s.cy), (3, 4, 3));
+        s.scroll_lines(false, 30, &b);
+        assert_eq!((s.row_off, s.cy), (9, 9));
+
+        // カーソルは画面の一番下の行に残る
+        s.scroll_lines(true, 5, &b);
+        assert_eq!((s.row_off, s.cy), (4, 6));
+        s.scroll_lines(true, 30, &b);
+        assert_eq!((s.row_off, s.cy), (0, 2));
+    }
+
+    #[test]
+    fn test_draw_row_selection() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 5)), i, o).unwrap();
+        let mut row = EditorRow::new(b"abc".to_vec(), DEFAULT_TAB_STOP);
+        row.hl = vec![
+            Highlight::Selection,
+            Highlight::Keyword,
+            Highlight::Selection,
+        ];
+        s.draw_row(&row);
+        assert_eq!(
+            String::from_utf8(s.buf).unwrap(),
+            "\x1b[39m\x1b[7ma\x1b[27m\x1b[33mb\x1b[39m\x1b[7mc\x1b[27m"
+        );
+    }
+
+    #[test]
+    fn test_setup_restore() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 10)), i, o).unwrap();
+        s.setup().unwrap();
+        assert_eq!(
+            s.output,
+            b"\x1b[?1049h\x1b[?2004h\x1b[?1002h\x1b[?1006h".to_vec()
+        );
+        s.output.clear();
+        s.restore().unwrap();
+        assert_eq!(
+            s.output,
+            b"\x1b[?1006l\x1b[?1002l\x1b[?2004l\x1b[?25h\x1b[?1049l".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_detect_kitty_keyboard() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 10)), i, o).unwrap();
+        let i = DummyInputSequences(vec![
+            InputSeq::new(KeySeq::Key(b'a')),
+            InputSeq::new(KeySeq::KeyboardFlags(0)),
+            InputSeq::new(KeySeq::Key(b'b')),
+            InputSeq::new(KeySeq::DeviceAttributes),
+        ]);
+        let mut typed = vec![];
+        assert!(s.detect_kitty_keyboard(i, &mut typed).unwrap());
+        // 答えを待つ間に押されたキーは残す
+        let typed: Vec<KeySeq> = typed.into_iter().map(|seq| seq.key).collect();
+        assert_eq!(typed, vec![KeySeq::Key(b'a'), KeySeq::Key(b'b')]);
+        assert_eq!(s.output, b"\x1b[?u\x1b[c".to_vec());
+
+        s.output.clear();
+        s.setup().unwrap();
+        assert!(s.output.ends_with(b"\x1b[>1u"));
+        s.output.clear();
+        s.restore().unwrap();
+        assert!(s.output.starts_with(b"\x1b[<u"));
+
+        // 対応していない端末
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 10)), i, o).unwrap();
+        let i = DummyInputSequences(vec![
+            InputSeq::new(KeySeq::DeviceAttributes),
+            InputSeq::new(KeySeq::KeyboardFlags(0)),
+        ]);
+        assert!(!s.detect_kitty_keyboard(i, &mut vec![]).unwrap());
+
+        // 何も答えない端末
+        let keys = (0..20)
+            .map(|_| InputSeq::new(KeySeq::Unidentified))
+            .collect();
+        let mut i = DummyInputSequences(keys);
+        assert!(!s.detect_kitty_keyboard(&mut i, &mut vec![]).unwrap());
+        assert_eq!(i.0.len(), 9);
+    }
+
+    #[test]
+    fn test_resize() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((20, 12)), i, o).unwrap();
+        let b = dummy_rows(20, b"hello");
+        s.cy = 9;
+        s.scroll(&b);
+        assert_eq!(s.row_off, 0);
+        s.shown = vec![b"hello".to_vec(); 12];
+
+        // 大きさが変わったら画面全体を描き直す
+        s.resize(Some((4, 7)), DummyInputSequences(vec![])).unwrap();
+        assert_eq!((s.cols, s.rows), (4, 5));
+        assert!(s.shown.is_empty());
+
+        // 小さくなってもカーソルが見えるようにする
+        s.cx = 5;
+        s.scroll(&b);
+        assert_eq!((s.row_off, s.col_off), (5, 2));
+    }
+
+    #[test]
+    fn test_draw_message_bar() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 100)), i, o).unwrap();
+        s.draw_message_bar();
+        assert_eq!(s.frame, vec![b"".to_vec()]);
+
+        s.frame = vec![];
+        s.set_message("hello kirocode");
+        assert_eq!(s.message(), Some("hello kirocode"));
+        s.draw_message_bar();
+        assert_eq!(s.frame, vec![b"hello kiro".to_vec()]);
+
+        // 時間が経ったメッセージは表示しない
+        if let Some(time) = Instant::now().checked_sub(MESSAGE_TIMEOUT) {
+            s.frame = vec![];
+            s.message = Some(("old".to_string(), time));
+            s.draw_message_bar();
+            assert_eq!(s.frame, vec![b"".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_draw_rows_render() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((50, 3)), i, o).unwrap();
+        let b = buffer(&[b"\ta\x01b"]);
+        s.draw_rows(&b, &plain_rows(&b, 4));
+        assert_eq!(s.frame, vec![b"    a^Ab".to_vec()]);
+    }
+
+    #[test]
+    fn test_scroll_render_column() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 100)), i, o).unwrap();
+        let b = buffer(&[b"\t\tabcdef"]);
+
+        // タブ 2 つの後ろは render 上では 16 桁目
+        s.cx = 2;
+        s.scroll(&b);
+        assert_eq!(s.rx, 16);
+        assert_eq!(s.col_off, 7);
+
+        s.draw_rows(&b, &plain_rows(&b, 8));
+        assert!(s.frame[0].starts_with(b"         a"));
+
+        s.cx = 0;
+        s.scroll(&b);
+        assert_eq!(s.rx, 0);
+        assert_eq!(s.col_off, 0);
+    }
+
+    #[test]
+    fn test_refresh_cursor_with_tab() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((50, 100)), i, o).unwrap();
+        s.set_tab_stop(4);
+        let b = buffer(&[b"\tx"]);
+        let status = Status {
+            filename: None,
+            filetype: None,
+            dirty: false,
+            indexing: None,
+            new_file: false,
+            read_only: false,
+        };
+        s.cx = 1;
+        s.refresh(&b, plain_rows(&b, 4), &status).unwrap();
+        assert!(s.output.ends_with(b"\x1b[1;5H\x1b[?25h"));
+    }
+
+    #[test]
+    fn test_draw_rows_wide() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((5, 3)), i, o).unwrap();
+        let b = buffer(&["あいう".as_bytes()]);
+
+        // 右端で途切れる全角文字は空白
+        s.draw_rows(&b, &plain_rows(&b, DEFAULT_TAB_STOP));
+        assert_eq!(s.frame, vec!["あい ".as_bytes()]);
+
+        // 左端で途切れる全角文字も空白
+        s.frame = vec![];
+        s.col_off = 1;
+        s.draw_rows(&b, &plain_rows(&b, DEFAULT_TAB_STOP));
+        assert_eq!(s.frame, vec![" いう".as_bytes()]);
+    }
+
+    #[test]
+    fn test_draw_rows_highlight() {
+        let i = DummyInputSequences(vec![]);
+        let o: Vec<u8> = vec![];
+        let mut s = Screen::new(Some((10, 3)), i, o).unwrap();
+        let b = buffer(&[b"ab 12"]);
+        let row = |y| {
+            let mut row = EditorRow::new(b.line(y), DEFAULT_TAB_STOP);
+            row.hl = vec![
+                Highlight::Keyword,
+                Highlight::Keyword,
+                Highlight::Normal,
+                Highlight::Number,
+                Highlight::Number,
+            ];
+            row
+        };
+
+        // 色が変わるところでだけ SGR を出し、行末で元に戻す
+        s.draw_rows(&b, &row);
+        assert_eq!(s.frame, vec![b"\x1b[33mab\x1b[39m \x1b[31m12\x1b[39m"]);
+    }
+
+    #[test]
+    fn test_move_cursor_utf8() {
+        let input = DummyInputSequences(vec![]);
+        let output: Vec<u8> = vec![];
+        let mut screen = Screen::new(Some((50, 100)), input, output).unwrap();
+        let rows = buffer(&["aあか\u{3099}".as_bytes(), b"abcdef"]);
+
+        // 書記素クラスタ単位で移動する
+        screen.move_cursor(Right, &rows);
+        assert_eq!(screen.cx, 1);
+        screen.move_cursor(Right, &rows);
+        assert_eq!(screen.cx, 4);
+        screen.move_cursor(Right, &rows);
+        assert_eq!(screen.cx, 10);
+        screen.move_cursor(Left, &rows);
+        assert_eq!(screen.cx, 4);
+
+        // 上下の移動は表示上の桁を保つ
+        screen.move_cursor(Down, &rows);
+        assert_eq!((screen.cx, screen.cy), (3, 1));
+        screen.move_cursor(Right, &rows);
+        screen.move_cursor(Up, &rows);
+        assert_eq!((screen.cx, screen.cy), (4, 0));
+    }
+
+    #[test]
+    fn test_truncate_width() {
+        assert_eq!(truncate_width("hello", 3), "hel");
+        assert_eq!(truncate_width("hello", 10), "hello");
+        assert_eq!(truncate_width("あいう", 3), "あ");
+        assert_eq!(truncate_width("あいう", 4), "あい");
     }
 }
@@ -0,0 +1,426 @@
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Highlight {
+    Normal,
+    Comment,
+    MlComment,
+    Keyword,
+    Type,
+    String,
+    Number,
+    Match,
+    Selection, // 文字色は付けずに反転表示する
+}
+
+impl Highlight {
+    // SGR の前景色
+    pub fn color(self) -> u8 {
+        use Highlight::*;
+        match self {
+            Normal => 39,
+            Comment | MlComment => 36,
+            Keyword => 33,
+            Type => 32,
+            String => 35,
+            Number => 31,
+            Match => 34,
+            Selection => 39,
+        }
+    }
+}
+
+// 行末の時点で何の途中にいるか. 次の行はこの状態から始まる
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HlState {
+    Normal,
+    BlockComment,
+    MlString(usize), // Syntax::ml_strings の何番目の区切りか
+}
+
+pub struct Syntax {
+    pub filetype: &'static str,
+    pub extensions: &'static [&'static str],
+    pub keywords: &'static [&'static str],
+    pub types: &'static [&'static str],
+    pub line_comment: Option<&'static str>,
+    pub block_comment: Option<(&'static str, &'static str)>,
+    // 1 行で閉じる文字列の引用符
+    pub quotes: &'static [u8],
+    // 複数行にまたがる文字列の区切り
+    pub ml_strings: &'static [&'static str],
+    pub numbers: bool,
+    // この文字で始まる行は見出し (Markdown)
+    pub heading: Option<u8>,
+    // [table] 形式の見出し (TOML)
+    pub section: bool,
+}
+
+pub static SYNTAXES: &[Syntax] = &[
+    Syntax {
+        filetype: "rust",
+        extensions: &["rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        types: &[
+            "bool", "char", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
+            "u128", "usize", "f32", "f64", "str", "String", "Vec", "Option", "Result", "Box",
+            "Some", "None", "Ok", "Err",
+        ],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        // ' はライフタイムと区別できないので文字列として扱わない
+        quotes: b"\"",
+        ml_strings: &[],
+        numbers: true,
+        heading: None,
+        section: false,
+    },
+    Syntax {
+        filetype: "c",
+        extensions: &["c", "h", "cpp", "hpp", "cc"],
+        keywords: &[
+            "switch", "if", "while", "for", "break", "continue", "return", "else", "struct",
+            "union", "typedef", "static", "enum", "class", "case", "default", "do", "goto",
+            "sizeof", "const", "extern", "volatile", "register", "inline", "#include", "#define",
+            "#if", "#ifdef", "#ifndef", "#else", "#endif",
+        ],
+        types: &[
+            "int", "long", "double", "float", "char", "unsigned", "signed", "void", "short",
+            "bool", "size_t",
+        ],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        quotes: b"\"'",
+        ml_strings: &[],
+        numbers: true,
+        heading: None,
+        section: false,
+    },
+    Syntax {
+        filetype: "python",
+        extensions: &["py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+            "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+            "with", "yield", "True", "False", "None", "self",
+        ],
+        types: &[
+            "int", "float", "complex", "str", "bytes", "bool", "list", "tuple", "dict", "set",
+            "object",
+        ],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: b"\"'",
+        ml_strings: &["\"\"\"", "'''"],
+        numbers: true,
+        heading: None,
+        section: false,
+    },
+    Syntax {
+        filetype: "markdown",
+        extensions: &["md", "markdown"],
+        keywords: &[],
+        types: &[],
+        line_comment: None,
+        block_comment: Some(("<!--", "-->")),
+        quotes: b"`",
+        ml_strings: &["```"],
+        numbers: false,
+        heading: Some(b'#'),
+        section: false,
+    },
+    Syntax {
+        filetype: "toml",
+        extensions: &["toml"],
+        keywords: &["true", "false"],
+        types: &[],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: b"\"'",
+        ml_strings: &["\"\"\"", "'''"],
+        numbers: true,
+        heading: None,
+        section: true,
+    },
+];
+
+impl Syntax {
+    // 拡張子からシンタックスを選ぶ
+    pub fn select<P: AsRef<Path>>(path: P) -> Option<&'static Syntax> {
+        let ext = path.as_ref().extension()?.to_str()?;
+        SYNTAXES
+            .iter()
+            .find(|syntax| syntax.extensions.contains(&ext))
+    }
+
+    // render の各バイトのハイライトと行末の状態を返す
+    pub fn highlight(&self, render: &[u8], state: HlState) -> (Vec<Highlight>, HlState) {
+        let mut hl = vec![Highlight::Normal; render.len()];
+        let mut state = state;
+        let mut i = 0;
+
+        if state == HlState::Normal {
+            if self.heading.is_some() && render.first() == self.heading.as_ref() {
+                fill(&mut hl, 0, render.len(), Highlight::Keyword);
+                return (hl, state);
+            }
+            if self.section && render.first() == Some(&b'[') {
+                let end = match render.iter().position(|&c| c == b']') {
+                    Some(end) => end + 1,
+                    None => render.len(),
+                };
+                fill(&mut hl, 0, end, Highlight::Type);
+                i = end;
+            }
+        }
+
+        let mut prev_sep = true;
+        while i < render.len() {
+            let rest = &render[i..];
+
+            match state {
+                HlState::BlockComment => {
+                    let (_, end) = self.block_comment.unwrap_or(("", ""));
+                    if !end.is_empty() && rest.starts_with(end.as_bytes()) {
+                        fill(&mut hl, i, i + end.len(), Highlight::MlComment);
+                        i += end.len();
+                        state = HlState::Normal;
+                        prev_sep = true;
+                    } else {
+                        hl[i] = Highlight::MlComment;
+                        i += 1;
+                    }
+                    continue;
+                }
+                HlState::MlString(k) => {
+                    let delim = self.ml_strings[k];
+                    if rest.starts_with(delim.as_bytes()) {
+                        fill(&mut hl, i, i + delim.len(), Highlight::String);
+                        i += delim.len();
+                        state = HlState::Normal;
+                        prev_sep = true;
+                    } else {
+                        hl[i] = Highlight::String;
+                        i += 1;
+                    }
+                    continue;
+                }
+                HlState::Normal => {}
+            }
+
+            if let Some(start) = self.line_comment {
+                if rest.starts_with(start.as_bytes()) {
+                    fill(&mut hl, i, render.len(), Highlight::Comment);
+                    break;
+                }
+            }
+
+            if let Some((start, _)) = self.block_comment {
+                if rest.starts_with(start.as_bytes()) {
+                    fill(&mut hl, i, i + start.len(), Highlight::MlComment);
+                    i += start.len();
+                    state = HlState::BlockComment;
+                    continue;
+                }
+            }
+
+            let ml_string = self
+                .ml_strings
+                .iter()
+                .position(|delim| rest.starts_with(delim.as_bytes()));
+            if let Some(k) = ml_string {
+                let len = self.ml_strings[k].len();
+                fill(&mut hl, i, i + len, Highlight::String);
+                i += len;
+                state = HlState::MlString(k);
+                continue;
+            }
+
+            let c = render[i];
+            if self.quotes.contains(&c) {
+                hl[i] = Highlight::String;
+                i += 1;
+                while i < render.len() {
+                    hl[i] = Highlight::String;
+                    if render[i] == b'\\' && i + 1 < render.len() {
+                        hl[i + 1] = Highlight::String;
+                        i += 2;
+                        continue;
+                    }
+                    i += 1;
+                    if render[i - 1] == c {
+                        break;
+                    }
+                }
+                prev_sep = true;
+                continue;
+            }
+
+            if self.numbers {
+                let prev_hl = if i > 0 { hl[i - 1] } else { Highlight::Normal };
+                if (c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number))
+                    || (c == b'.' && prev_hl == Highlight::Number)
+                {
+                    hl[i] = Highlight::Number;
+                    i += 1;
+                    prev_sep = false;
+                    continue;
+                }
+            }
+
+            if prev_sep {
+                if let Some((len, class)) = self.match_keyword(rest) {
+                    fill(&mut hl, i, i + len, class);
+                    i += len;
+                    prev_sep = false;
+                    continue;
+                }
+            }
+
+            prev_sep = is_separator(c);
+            i += 1;
+        }
+
+        (hl, state)
+    }
+
+    // rest の先頭がキーワードか型名なら、その長さと種類を返す
+    fn match_keyword(&self, rest: &[u8]) -> Option<(usize, Highlight)> {
+        let words = self
+            .keywords
+            .iter()
+            .map(|w| (w, Highlight::Keyword))
+            .chain(self.types.iter().map(|w| (w, Highlight::Type)));
+        for (word, class) in words {
+            let len = word.len();
+            if rest.starts_with(word.as_bytes()) && rest.get(len).is_none_or(|&c| is_separator(c)) {
+                return Some((len, class));
+            }
+        }
+        None
+    }
+}
+
+fn fill(hl: &mut [Highlight], start: usize, end: usize, class: Highlight) {
+    for h in hl[start..end].iter_mut() {
+        *h = class;
+    }
+}
+
+fn is_separator(c: u8) -> bool {
+    c.is_ascii_whitespace() || c == 0 || b",.()+-/*=~%<>[];{}:&|!^?".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Highlight::*;
+
+    fn syntax(filetype: &str) -> &'static Syntax {
+        SYNTAXES.iter().find(|s| s.filetype == filetype).unwrap()
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(Syntax::select("main.rs").unwrap().filetype, "rust");
+        assert_eq!(Syntax::select("a/b/kilo.c").unwrap().filetype, "c");
+        assert_eq!(Syntax::select("setup.py").unwrap().filetype, "python");
+        assert_eq!(Syntax::select("README.md").unwrap().filetype, "markdown");
+        assert_eq!(Syntax::select("Cargo.toml").unwrap().filetype, "toml");
+        assert!(Syntax::select("test.txt").is_none());
+        assert!(Syntax::select("Makefile").is_none());
+    }
+
+    #[test]
+    fn test_highlight_rust() {
+        let rust = syntax("rust");
+        let (hl, state) = rust.highlight(b"let x: u8 = 12; // hi", HlState::Normal);
+        assert_eq!(&hl[0..3], &[Keyword; 3]);
+        assert_eq!(hl[3], Normal);
+        assert_eq!(&hl[7..9], &[Type; 2]);
+        assert_eq!(&hl[12..14], &[Number; 2]);
+        assert_eq!(&hl[16..], &[Comment; 5]);
+        assert_eq!(state, HlState::Normal);
+
+        // 識別子の一部はキーワードにしない
+        let (hl, _) = rust.highlight(b"letter", HlState::Normal);
+        assert_eq!(hl, vec![Normal; 6]);
+
+        // 文字列の中のエスケープ
+        let (hl, _) = rust.highlight(br#"a"b\"c"d"#, HlState::Normal);
+        assert_eq!(hl[0], Normal);
+        assert_eq!(&hl[1..7], &[String; 6]);
+        assert_eq!(hl[7], Normal);
+    }
+
+    #[test]
+    fn test_highlight_block_comment() {
+        let c = syntax("c");
+        let (hl, state) = c.highlight(b"int a; /* comment", HlState::Normal);
+        assert_eq!(&hl[0..3], &[Type; 3]);
+        assert_eq!(&hl[7..], &[MlComment; 10]);
+        assert_eq!(state, HlState::BlockComment);
+
+        let (hl, state) = c.highlight(b"still */ 1", state);
+        assert_eq!(&hl[0..8], &[MlComment; 8]);
+        assert_eq!(hl[9], Number);
+        assert_eq!(state, HlState::Normal);
+    }
+
+    #[test]
+    fn test_highlight_python() {
+        let py = syntax("python");
+        let (hl, state) = py.highlight(b"def f(): '''doc", HlState::Normal);
+        assert_eq!(&hl[0..3], &[Keyword; 3]);
+        assert_eq!(&hl[9..], &[String; 6]);
+        assert_eq!(state, HlState::MlString(1));
+
+        // 別の区切りでは閉じない
+        let (_, state) = py.highlight(b"\"\"\"", state);
+        assert_eq!(state, HlState::MlString(1));
+        let (hl, state) = py.highlight(b"''' # c", state);
+        assert_eq!(&hl[0..3], &[String; 3]);
+        assert_eq!(&hl[4..], &[Comment; 3]);
+        assert_eq!(state, HlState::Normal);
+    }
+
+    #[test]
+    fn test_highlight_markdown() {
+        let md = syntax("markdown");
+        let (hl, _) = md.highlight(b"# Title", HlState::Normal);
+        assert_eq!(hl, vec![Keyword; 7]);
+
+        let (hl, _) = md.highlight(b"use `code` here", HlState::Normal);
+        assert_eq!(&hl[4..10], &[String; 6]);
+        assert_eq!(hl[11], Normal);
+
+        let (_, state) = md.highlight(b"```rust", HlState::Normal);
+        assert_eq!(state, HlState::MlString(0));
+        // コードブロックの中の # は見出しではない
+        let (hl, state) = md.highlight(b"# not heading", state);
+        assert_eq!(hl, vec![String; 13]);
+        let (_, state) = md.highlight(b"```", state);
+        assert_eq!(state, HlState::Normal);
+    }
+
+    #[test]
+    fn test_highlight_toml() {
+        let toml = syntax("toml");
+        let (hl, _) = toml.highlight(b"[package] # c", HlState::Normal);
+        assert_eq!(&hl[0..9], &[Type; 9]);
+        assert_eq!(&hl[10..], &[Comment; 3]);
+
+        let (hl, _) = toml.highlight(b"a = \"x\"", HlState::Normal);
+        assert_eq!(&hl[4..], &[String; 3]);
+
+        let (hl, _) = toml.highlight(b"b = true", HlState::Normal);
+        assert_eq!(&hl[4..], &[Keyword; 4]);
+    }
+}
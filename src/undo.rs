@@ -0,0 +1,153 @@
+// バッファに対する 1 回の変更. at はバイト位置. inverse() を適用すれば元に戻る
+#[derive(Clone, PartialEq, Debug)]
+pub enum Change {
+    Insert { at: usize, bytes: Vec<u8> },
+    Delete { at: usize, bytes: Vec<u8> },
+}
+
+impl Change {
+    pub fn inverse(&self) -> Change {
+        match self.clone() {
+            Change::Insert { at, bytes } => Change::Delete { at, bytes },
+            Change::Delete { at, bytes } => Change::Insert { at, bytes },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EditKind {
+    InsertChar,
+    Other,
+}
+
+// 取り消しの単位. 変更の前後のカーソル位置 (cx, cy) を持つ
+#[derive(Clone, PartialEq, Debug)]
+pub struct Transaction {
+    pub kind: EditKind,
+    pub changes: Vec<Change>,
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Transaction>,
+    redo: Vec<Transaction>,
+    // true なら次の文字入力を直前の取り消し単位にまとめない
+    sealed: bool,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tx: Transaction) {
+        self.redo.clear();
+
+        // 続けて入力した文字は 1 つにまとめる
+        if let Some(last) = self.undo.last_mut() {
+            if !self.sealed
+                && tx.kind == EditKind::InsertChar
+                && last.kind == EditKind::InsertChar
+                && last.after == tx.before
+            {
+                last.changes.extend(tx.changes);
+                last.after = tx.after;
+                return;
+            }
+        }
+        self.undo.push(tx);
+        self.sealed = false;
+    }
+
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    // 取り消す変更を返す. 返した変更はやり直しに回す
+    pub fn undo(&mut self) -> Option<Transaction> {
+        let tx = self.undo.pop()?;
+        self.redo.push(tx.clone());
+        self.sealed = true;
+        Some(tx)
+    }
+
+    pub fn redo(&mut self) -> Option<Transaction> {
+        let tx = self.redo.pop()?;
+        self.undo.push(tx.clone());
+        self.sealed = true;
+        Some(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(x: usize, c: u8) -> Transaction {
+        Transaction {
+            kind: EditKind::InsertChar,
+            changes: vec![Change::Insert {
+                at: x,
+                bytes: vec![c],
+            }],
+            before: (x, 0),
+            after: (x + 1, 0),
+        }
+    }
+
+    #[test]
+    fn test_inverse() {
+        let change = Change::Insert {
+            at: 2,
+            bytes: b"ab".to_vec(),
+        };
+        assert_eq!(
+            change.inverse(),
+            Change::Delete {
+                at: 2,
+                bytes: b"ab".to_vec()
+            }
+        );
+        assert_eq!(change.inverse().inverse(), change);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut h = History::new();
+        h.push(insert(0, b'a'));
+        h.push(insert(1, b'b'));
+        let tx = h.undo().unwrap();
+        assert_eq!(tx.changes.len(), 2);
+        assert_eq!((tx.before, tx.after), ((0, 0), (2, 0)));
+        assert!(h.undo().is_none());
+
+        // カーソルが飛んだらまとめない
+        let mut h = History::new();
+        h.push(insert(0, b'a'));
+        h.push(insert(5, b'b'));
+        assert_eq!(h.undo().unwrap().changes.len(), 1);
+
+        // seal したらまとめない
+        let mut h = History::new();
+        h.push(insert(0, b'a'));
+        h.seal();
+        h.push(insert(1, b'b'));
+        assert_eq!(h.undo().unwrap().changes.len(), 1);
+    }
+
+    #[test]
+    fn test_redo() {
+        let mut h = History::new();
+        h.push(insert(0, b'a'));
+        assert!(h.redo().is_none());
+        let tx = h.undo().unwrap();
+        assert_eq!(h.redo().unwrap(), tx);
+
+        // 新しく変更したらやり直しは消える
+        h.undo().unwrap();
+        h.push(insert(0, b'b'));
+        assert!(h.redo().is_none());
+    }
+}